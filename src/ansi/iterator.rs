@@ -1,5 +1,6 @@
 use anstyle_parse::{Params, ParamsIter};
 use core::str::Bytes;
+use std::collections::VecDeque;
 use std::convert::TryFrom;
 use std::iter;
 
@@ -7,12 +8,17 @@ pub struct AnsiElementIterator<'a> {
     // The input bytes
     bytes: Bytes<'a>,
 
+    // The whole input, for looking ahead over strings the parser is not given
+    input: &'a [u8],
+
     // The state machine
     machine: anstyle_parse::Parser,
 
-    // Becomes non-None when the parser finishes parsing an ANSI sequence.
-    // This is never Element::Text.
-    element: Option<Element>,
+    // Kept between bytes, as a DCS string is dispatched over many of them
+    performer: Performer,
+
+    // Elements ready to be emitted, in order.
+    ready: VecDeque<Element>,
 
     // Number of text bytes seen since the last element was emitted.
     text_length: usize,
@@ -32,6 +38,9 @@ struct Performer {
 
     // Number of text bytes seen since the last element was emitted.
     text_length: usize,
+
+    // The DCS string being received, between `hook` and `unhook`.
+    dcs: Option<Dcs>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -39,17 +48,66 @@ pub enum Element {
     Sgr(ansi_term::Style, usize, usize),
     Csi(usize, usize),
     Esc(usize, usize),
-    Osc(usize, usize),
+    Osc(Osc, usize, usize),
+    Dcs(Dcs, usize, usize),
+    Opaque(OpaqueKind, usize, usize),
     Text(usize, usize),
 }
 
+/// Strings whose contents are data for the terminal, such as images, and are passed on whole.
+/// They run to the next ST, `ESC \\`. Only the 7-bit introducers are recognised; the 8-bit C1
+/// forms are text.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpaqueKind {
+    // `ESC _`, e.g. kitty graphics
+    Apc,
+    // `ESC ^`
+    Pm,
+    // `ESC X`
+    Sos,
+    // `ESC P tmux;`, a sequence for the terminal outside tmux, with each ESC in it doubled
+    TmuxPassthrough,
+}
+
+/// The payload of an OSC sequence, `ESC ] command ; param ; ... ST`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Osc {
+    // E.g. 8 for a hyperlink or 133 for a prompt mark; None if the command is not a number
+    pub command: Option<u32>,
+    // The `;`-separated parameters after the command. The parser keeps at most 15.
+    pub params: Vec<String>,
+    pub bell_terminated: bool,
+}
+
+impl Osc {
+    /// The parameters from `index` on, joined by `;` again: for a trailing parameter such as a
+    /// URI or a title, which may itself contain `;`.
+    pub fn params_from(&self, index: usize) -> Option<String> {
+        (index < self.params.len()).then(|| self.params[index..].join(";"))
+    }
+}
+
+/// A DCS sequence, `ESC P params intermediates action data ST`, e.g. a sixel image
+/// (`ESC P q ...`) or a DECRQSS request (`ESC P $ q m ST`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dcs {
+    pub params: Vec<Vec<u16>>,
+    pub intermediates: Vec<u8>,
+    pub action: char,
+    pub data: Vec<u8>,
+    // Whether the parser gave up on parameters or intermediates, as there were too many
+    pub ignore: bool,
+}
+
 impl Element {
     fn set_range(&mut self, start: usize, end: usize) {
         let (from, to) = match self {
             Element::Sgr(_, from, to) => (from, to),
             Element::Csi(from, to) => (from, to),
             Element::Esc(from, to) => (from, to),
-            Element::Osc(from, to) => (from, to),
+            Element::Osc(_, from, to) => (from, to),
+            Element::Dcs(_, from, to) => (from, to),
+            Element::Opaque(_, from, to) => (from, to),
             Element::Text(from, to) => (from, to),
         };
 
@@ -62,20 +120,83 @@ impl<'a> AnsiElementIterator<'a> {
     pub fn new(s: &'a str) -> Self {
         Self {
             machine: anstyle_parse::Parser::<anstyle_parse::DefaultCharAccumulator>::new(),
+            performer: Performer::default(),
             bytes: s.bytes(),
-            element: None,
+            input: s.as_bytes(),
+            ready: VecDeque::new(),
             text_length: 0,
             start: 0,
             pos: 0,
         }
     }
 
-    fn advance_vte(&mut self, byte: u8) {
-        let mut performer = Performer::default();
-        self.machine.advance(&mut performer, byte);
-        self.element = performer.element;
-        self.text_length += performer.text_length;
+    // Feed a byte to the parser, returning whether it ended a sequence.
+    fn advance_vte(&mut self, byte: u8) -> bool {
+        self.machine.advance(&mut self.performer, byte);
         self.pos += 1;
+        let text_length = std::mem::take(&mut self.performer.text_length);
+
+        if text_length > 0 {
+            // Bytes between the current text and this text that were neither text nor part of a
+            // dispatched sequence belong to a sequence the parser ignored or abandoned, e.g. a
+            // CSI with too many intermediates or an APC string. They are emitted as one element
+            // rather than being taken for text.
+            let text_start = self.pos - text_length;
+            if text_start > self.start + self.text_length {
+                self.push_text();
+                self.ready.push_back(Element::Esc(self.start, text_start));
+                self.start = text_start;
+            }
+            self.text_length += text_length;
+        }
+
+        if let Some(mut element) = self.performer.element.take() {
+            self.push_text();
+            element.set_range(self.start, self.pos);
+            self.ready.push_back(element);
+            self.start = self.pos;
+            return true;
+        }
+        false
+    }
+
+    // If the ESC just parsed starts an opaque string, emit the string without parsing it: the
+    // parser would dispatch the sequences inside a tmux passthrough string, with their ESCs
+    // undoubled, and would split it where they end.
+    fn take_opaque_string(&mut self) {
+        let esc = self.pos - 1;
+        let rest = &self.input[esc..];
+        let kind = match rest.get(1) {
+            Some(b'_') => OpaqueKind::Apc,
+            Some(b'^') => OpaqueKind::Pm,
+            Some(b'X') => OpaqueKind::Sos,
+            Some(b'P') if rest[2..].starts_with(b"tmux;") => OpaqueKind::TmuxPassthrough,
+            _ => return,
+        };
+        let end = esc + opaque_string_len(kind, rest);
+
+        self.push_text();
+        // An abandoned sequence before the ESC
+        if self.start < esc {
+            self.ready.push_back(Element::Esc(self.start, esc));
+        }
+        self.ready.push_back(Element::Opaque(kind, esc, end));
+        if end > self.pos {
+            self.bytes.nth(end - self.pos - 1);
+        }
+        self.pos = end;
+        self.start = end;
+        self.machine = anstyle_parse::Parser::default();
+    }
+
+    // Queue the text seen since the last element, if any.
+    fn push_text(&mut self) {
+        if self.text_length > 0 {
+            let end = self.start + self.text_length;
+            self.ready.push_back(Element::Text(self.start, end));
+            self.start = end;
+            self.text_length = 0;
+        }
     }
 }
 
@@ -83,40 +204,50 @@ impl Iterator for AnsiElementIterator<'_> {
     type Item = Element;
 
     fn next(&mut self) -> Option<Element> {
-        // If the last element emitted was text, then there may be a non-text element waiting
-        // to be emitted. In that case we do not consume a new byte.
-        while self.element.is_none() {
+        while self.ready.is_empty() {
             match self.bytes.next() {
-                Some(b) => self.advance_vte(b),
-                None => break,
-            }
-        }
-
-        if let Some(mut element) = self.element.take() {
-            // There is a non-text element waiting to be emitted, but it may have preceding
-            // text, which must be emitted first.
-            if self.text_length > 0 {
-                let start = self.start;
-                self.start += self.text_length;
-                self.text_length = 0;
-                self.element = Some(element);
-                return Some(Element::Text(start, self.start));
+                Some(b) => {
+                    if self.advance_vte(b) {
+                        continue;
+                    }
+                    if b == 0x1b {
+                        self.take_opaque_string();
+                    }
+                }
+                None => {
+                    self.push_text();
+                    // An unterminated sequence at the end of the input
+                    if self.start < self.pos {
+                        self.ready.push_back(Element::Esc(self.start, self.pos));
+                        self.start = self.pos;
+                    }
+                    break;
+                }
             }
-
-            let start = self.start;
-            self.start = self.pos;
-            element.set_range(start, self.pos);
-
-            return Some(element);
         }
+        self.ready.pop_front()
+    }
+}
 
-        if self.text_length > 0 {
-            self.text_length = 0;
-            return Some(Element::Text(self.start, self.pos));
+// The length of the opaque string at the start of `s`, up to and including its ST, or all of `s`
+// if it is unterminated.
+fn opaque_string_len(kind: OpaqueKind, s: &[u8]) -> usize {
+    let mut i = if kind == OpaqueKind::TmuxPassthrough {
+        "\x1bPtmux;".len()
+    } else {
+        2
+    };
+    while i < s.len() {
+        match (s[i], s.get(i + 1)) {
+            (0x1b, Some(b'\\')) => return i + 2,
+            // A doubled ESC inside tmux passthrough
+            (0x1b, Some(0x1b)) if kind == OpaqueKind::TmuxPassthrough => i += 2,
+            // Any other ESC ends the string, and starts a sequence of its own
+            (0x1b, Some(_)) => return i,
+            _ => i += 1,
         }
-
-        None
     }
+    s.len()
 }
 
 // Based on https://github.com/alacritty/vte/blob/v0.9.0/examples/parselog.rs
@@ -154,14 +285,48 @@ impl anstyle_parse::Perform for Performer {
         }
     }
 
-    fn hook(&mut self, _params: &Params, _intermediates: &[u8], _ignore: bool, _byte: u8) {}
+    fn hook(&mut self, params: &Params, intermediates: &[u8], ignore: bool, byte: u8) {
+        self.dcs = Some(Dcs {
+            params: params.iter().map(|param| param.to_vec()).collect(),
+            intermediates: intermediates.to_vec(),
+            action: byte as char,
+            data: Vec::new(),
+            ignore,
+        });
+    }
 
-    fn put(&mut self, _byte: u8) {}
+    fn put(&mut self, byte: u8) {
+        if let Some(dcs) = &mut self.dcs {
+            dcs.data.push(byte);
+        }
+    }
 
-    fn unhook(&mut self) {}
+    fn unhook(&mut self) {
+        self.element = self.dcs.take().map(|dcs| Element::Dcs(dcs, 0, 0));
+    }
 
-    fn osc_dispatch(&mut self, _params: &[&[u8]], _bell_terminated: bool) {
-        self.element = Some(Element::Osc(0, 0));
+    fn osc_dispatch(&mut self, params: &[&[u8]], bell_terminated: bool) {
+        let (command, params) = match params.split_first() {
+            Some((command, params)) => (
+                std::str::from_utf8(command)
+                    .ok()
+                    .and_then(|c| c.parse().ok()),
+                params
+                    .iter()
+                    .map(|param| String::from_utf8_lossy(param).into_owned())
+                    .collect(),
+            ),
+            None => (None, Vec::new()),
+        };
+        self.element = Some(Element::Osc(
+            Osc {
+                command,
+                params,
+                bell_terminated,
+            },
+            0,
+            0,
+        ));
     }
 
     fn esc_dispatch(&mut self, _intermediates: &[u8], _ignore: bool, _byte: u8) {
@@ -280,7 +445,7 @@ fn parse_sgr_color(params: &mut dyn Iterator<Item = u16>) -> Option<ansi_term::C
 #[cfg(test)]
 mod tests {
 
-    use super::{AnsiElementIterator, Element};
+    use super::{AnsiElementIterator, Dcs, Element, OpaqueKind, Osc};
 
     #[test]
     fn test_iterator_1() {
@@ -382,6 +547,127 @@ mod tests {
         assert_eq!("あ.", &s[3..7]);
     }
 
+    #[test]
+    fn test_iterator_ignored_and_unterminated_sequences() {
+        // A CSI with two intermediates is ignored by the parser, but is not text
+        let s = "a\x1b[1 !pb\x1b_Gq#0;1\x1b\\c\x1b[3";
+        let actual_elements: Vec<Element> = AnsiElementIterator::new(s).collect();
+        assert_eq!(
+            actual_elements,
+            vec![
+                Element::Text(0, 1),
+                Element::Esc(1, 7),
+                Element::Text(7, 8),
+                Element::Opaque(OpaqueKind::Apc, 8, 18),
+                Element::Text(18, 19),
+                Element::Esc(19, 22),
+            ]
+        );
+        assert_eq!(crate::ansi::strip_ansi_codes(s), "abc");
+    }
+
+    fn hyperlink(uri: &str) -> Osc {
+        Osc {
+            command: Some(8),
+            params: vec![String::new(), uri.to_string()],
+            bell_terminated: false,
+        }
+    }
+
+    #[test]
+    fn test_iterator_osc_payloads() {
+        let s = "\x1b]7;file://host/home/me\x07\x1b]8;id=1;https://x.test/?a=1;b=2\x07\x1b]133;A\x07\x1b]2;vim ü\x07\x1b]x;y\x07";
+        let payloads: Vec<Osc> = AnsiElementIterator::new(s)
+            .filter_map(|element| match element {
+                Element::Osc(osc, _, _) => Some(osc),
+                _ => None,
+            })
+            .collect();
+        let commands: Vec<Option<u32>> = payloads.iter().map(|osc| osc.command).collect();
+        assert_eq!(commands, vec![Some(7), Some(8), Some(133), Some(2), None]);
+        assert_eq!(payloads[0].params, vec!["file://host/home/me"]);
+        assert!(payloads[0].bell_terminated);
+        assert_eq!(payloads[1].params[0], "id=1");
+        assert_eq!(
+            payloads[1].params_from(1).as_deref(),
+            Some("https://x.test/?a=1;b=2")
+        );
+        assert_eq!(payloads[2].params, vec!["A"]);
+        assert_eq!(payloads[3].params_from(0).as_deref(), Some("vim ü"));
+        assert_eq!(payloads[3].params_from(1), None);
+    }
+
+    #[test]
+    fn test_iterator_dcs() {
+        let s = "a\x1bP0;1q#0;2;0;0;0#0!10~\x1b\\b\x1bP$qm\x1b\\";
+        let actual_elements: Vec<Element> = AnsiElementIterator::new(s).collect();
+        assert_eq!(
+            actual_elements,
+            vec![
+                Element::Text(0, 1),
+                Element::Dcs(
+                    Dcs {
+                        params: vec![vec![0], vec![1]],
+                        intermediates: vec![],
+                        action: 'q',
+                        data: b"#0;2;0;0;0#0!10~".to_vec(),
+                        ignore: false,
+                    },
+                    1,
+                    24
+                ),
+                Element::Esc(24, 25),
+                Element::Text(25, 26),
+                Element::Dcs(
+                    Dcs {
+                        params: vec![vec![0]],
+                        intermediates: b"$".to_vec(),
+                        action: 'q',
+                        data: b"m".to_vec(),
+                        ignore: false,
+                    },
+                    26,
+                    32
+                ),
+                Element::Esc(32, 33),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_iterator_opaque_strings() {
+        let s = "a\x1b_Gf=100;iVBOR/x.rs:1\x1b\\b\x1bPtmux;\x1b\x1b]8;;x\x07c.rs:2\x1b\x1b[0m\x1b\\d\x1b^pm\x1b[1mtext\x1bXsos";
+        let actual_elements: Vec<Element> = AnsiElementIterator::new(s).collect();
+        assert_eq!(
+            actual_elements,
+            vec![
+                Element::Text(0, 1),
+                Element::Opaque(OpaqueKind::Apc, 1, 24),
+                Element::Text(24, 25),
+                Element::Opaque(OpaqueKind::TmuxPassthrough, 25, 53),
+                Element::Text(53, 54),
+                // Unterminated: ended by the next sequence, or the end of the input
+                Element::Opaque(OpaqueKind::Pm, 54, 58),
+                Element::Sgr(
+                    ansi_term::Style {
+                        is_bold: true,
+                        ..ansi_term::Style::default()
+                    },
+                    58,
+                    62
+                ),
+                Element::Text(62, 66),
+                Element::Opaque(OpaqueKind::Sos, 66, 71),
+            ]
+        );
+        assert_eq!(crate::ansi::strip_ansi_codes(s), "abdtext");
+
+        // 8-bit C1 introducers are not recognised
+        let s = "a\u{9d}52;c;aGk=\u{9c}b";
+        let actual_elements: Vec<Element> = AnsiElementIterator::new(s).collect();
+        assert_eq!(actual_elements, vec![Element::Text(0, s.len())]);
+    }
+
     #[test]
     fn test_iterator_osc_hyperlinks_styled_non_ascii() {
         let s = "\x1b[38;5;4m\x1b]8;;file:///Users/dan/src/delta/src/ansi/mod.rs\x1b\\src/ansi/modバー.rs\x1b]8;;\x1b\\\x1b[0m\n";
@@ -408,10 +694,14 @@ mod tests {
                     0,
                     9
                 ),
-                Element::Osc(9, 58),
+                Element::Osc(
+                    hyperlink("file:///Users/dan/src/delta/src/ansi/mod.rs"),
+                    9,
+                    58
+                ),
                 Element::Esc(58, 59),
                 Element::Text(59, 80),
-                Element::Osc(80, 86),
+                Element::Osc(hyperlink(""), 80, 86),
                 Element::Esc(86, 87),
                 Element::Sgr(ansi_term::Style::default(), 87, 91),
                 Element::Text(91, 92),
@@ -1,10 +1,13 @@
 #![allow(dead_code)]
 
 pub mod iterator;
+pub mod stream;
 
 mod console_tests;
 
 use std::borrow::Cow;
+use std::iter::Peekable;
+use std::ops::Range;
 
 use ansi_term::Style;
 use itertools::Itertools;
@@ -146,7 +149,9 @@ pub fn ansi_preserving_slice(s: &str, start: usize) -> String {
                 Element::Sgr(_, a, b) => &s[a..b],
                 Element::Csi(a, b) => &s[a..b],
                 Element::Esc(a, b) => &s[a..b],
-                Element::Osc(a, b) => &s[a..b],
+                Element::Osc(_, a, b) => &s[a..b],
+                Element::Dcs(_, a, b) => &s[a..b],
+                Element::Opaque(_, a, b) => &s[a..b],
                 Element::Text(a, b) => {
                     let i = *index;
                     *index += b - a;
@@ -181,12 +186,141 @@ pub fn ansi_preserving_index(s: &str, i: usize) -> Option<usize> {
     None
 }
 
+/// Text with its ANSI escape sequences removed, together with where each piece of it came from,
+/// so that a match in the stripped text can be mapped back to a byte range of the original.
+pub struct StrippedText {
+    pub text: String,
+    // (offset in `text`, offset in the original) of the start of each text element
+    segments: Vec<(usize, usize)>,
+    // Byte ranges of the SGR sequences in the original
+    sgr_ranges: Vec<(usize, usize)>,
+    // The OSC 8 sequences that open and close hyperlinks, in order
+    pub hyperlink_marks: Vec<HyperlinkMark>,
+    // Byte ranges of the other escape sequences in the original, in order
+    pub sequences: Vec<(usize, usize)>,
+}
+
+/// An OSC 8 sequence in a `StrippedText`.
+pub struct HyperlinkMark {
+    // Offset in the stripped text
+    pub offset: usize,
+    // Byte range of the sequence in the original
+    pub sequence: (usize, usize),
+    // Whether it opens a link rather than closing one (with an empty URI)
+    pub opens: bool,
+}
+
+impl StrippedText {
+    pub fn new(s: &str) -> Self {
+        let mut text = String::with_capacity(s.len());
+        let mut segments = Vec::new();
+        let mut sgr_ranges = Vec::new();
+        let mut hyperlink_marks = Vec::new();
+        let mut sequences = Vec::new();
+        let mut elements = AnsiElementIterator::new(s).peekable();
+        while let Some(element) = elements.next() {
+            match element {
+                Element::Text(a, b) => {
+                    segments.push((text.len(), a));
+                    text.push_str(&s[a..b]);
+                }
+                Element::Sgr(_, a, b) => sgr_ranges.push((a, b)),
+                Element::Osc(osc, a, b) => {
+                    let b = with_string_terminator(s, b, &mut elements);
+                    match osc.command.zip(osc.params_from(1)) {
+                        Some((8, uri)) => hyperlink_marks.push(HyperlinkMark {
+                            offset: text.len(),
+                            sequence: (a, b),
+                            opens: !uri.is_empty(),
+                        }),
+                        _ => sequences.push((a, b)),
+                    }
+                }
+                Element::Dcs(_, a, b) => {
+                    let b = with_string_terminator(s, b, &mut elements);
+                    sequences.push((a, b));
+                }
+                Element::Csi(a, b) | Element::Esc(a, b) | Element::Opaque(_, a, b) => {
+                    sequences.push((a, b))
+                }
+            }
+        }
+        Self {
+            text,
+            segments,
+            sgr_ranges,
+            hyperlink_marks,
+            sequences,
+        }
+    }
+
+    /// The byte range of the original string that `start..end` of the stripped text came from.
+    /// The range starts at the first text byte, so escape sequences before it are left out, and
+    /// includes every escape sequence between its first and last text bytes, together with any
+    /// SGR sequences directly following it, so that styling that ends with the span (typically a
+    /// reset) stays inside it. None if the range is empty or out of bounds.
+    pub fn original_range(&self, start: usize, end: usize) -> Option<(usize, usize)> {
+        if start >= end || end > self.text.len() {
+            return None;
+        }
+        let original_offset = |i: usize| {
+            let segment = self.segments.partition_point(|&(offset, _)| offset <= i) - 1;
+            let (offset, original) = self.segments[segment];
+            original + (i - offset)
+        };
+        let original_start = original_offset(start);
+        // Offset just past the last text byte
+        let mut original_end = original_offset(end - 1) + 1;
+        while let Ok(i) = self
+            .sgr_ranges
+            .binary_search_by_key(&original_end, |&(a, _)| a)
+        {
+            original_end = self.sgr_ranges[i].1;
+        }
+        Some((original_start, original_end))
+    }
+}
+
+// The end of an OSC or DCS string ending at `end`, extended over the `\` of an ESC \ terminator,
+// which is parsed as an element of its own.
+fn with_string_terminator(
+    s: &str,
+    end: usize,
+    elements: &mut Peekable<AnsiElementIterator>,
+) -> usize {
+    if s[..end].ends_with('\x1b') && elements.peek() == Some(&Element::Esc(end, end + 1)) {
+        elements.next();
+        return end + 1;
+    }
+    end
+}
+
+/// The URI of an OSC 8 hyperlink sequence, `ESC ] 8 ; params ; URI` followed by its terminator,
+/// which is empty for the sequence that closes a link. None for other sequences.
+pub fn hyperlink_uri(osc: &str) -> Option<&str> {
+    hyperlink_uri_range(osc).map(|range| &osc[range])
+}
+
+/// The byte range of the URI within an OSC 8 hyperlink sequence.
+pub fn hyperlink_uri_range(osc: &str) -> Option<Range<usize>> {
+    let rest = osc.strip_prefix("\x1b]8;")?;
+    let rest = ["\x1b\\", "\x07", "\x1b"]
+        .iter()
+        .find_map(|terminator| rest.strip_suffix(terminator))
+        .unwrap_or(rest);
+    let params_len = rest.find(';')?;
+    let start = "\x1b]8;".len() + params_len + 1;
+    Some(start..start + rest.len() - params_len - 1)
+}
+
 fn ansi_strings_iterator(s: &str) -> impl Iterator<Item = (&str, bool)> {
     AnsiElementIterator::new(s).map(move |el| match el {
         Element::Sgr(_, i, j) => (&s[i..j], true),
         Element::Csi(i, j) => (&s[i..j], true),
         Element::Esc(i, j) => (&s[i..j], true),
-        Element::Osc(i, j) => (&s[i..j], true),
+        Element::Osc(_, i, j) => (&s[i..j], true),
+        Element::Dcs(_, i, j) => (&s[i..j], true),
+        Element::Opaque(_, i, j) => (&s[i..j], true),
         Element::Text(i, j) => (&s[i..j], false),
     })
 }
@@ -205,8 +339,9 @@ mod tests {
 
     // Note that src/ansi/console_tests.rs contains additional test coverage for this module.
     use super::{
-        ansi_preserving_index, ansi_preserving_slice, measure_text_width, parse_first_style,
-        string_starts_with_ansi_style_sequence, strip_ansi_codes, truncate_str, truncate_str_short,
+        ansi_preserving_index, ansi_preserving_slice, hyperlink_uri, measure_text_width,
+        parse_first_style, string_starts_with_ansi_style_sequence, strip_ansi_codes, truncate_str,
+        truncate_str_short, StrippedText,
     };
 
     #[test]
@@ -305,6 +440,74 @@ mod tests {
         assert_eq!(ansi_preserving_index(raw_string, 7), Some(24));
     }
 
+    #[test]
+    fn test_stripped_text_original_range() {
+        let s = "  File \x1b[35m\"x.py\"\x1b[0m, line \x1b[35m5\x1b[0m\x1b[K, in f";
+        let stripped = StrippedText::new(s);
+        assert_eq!(stripped.text, "  File \"x.py\", line 5, in f");
+        assert_eq!(stripped.text, strip_ansi_codes(s));
+
+        let range = |start, end| stripped.original_range(start, end).map(|(a, b)| &s[a..b]);
+        // Internal and trailing SGR sequences are included, other trailing sequences are not
+        assert_eq!(
+            range(2, 21),
+            Some("File \x1b[35m\"x.py\"\x1b[0m, line \x1b[35m5\x1b[0m")
+        );
+        // Leading sequences are not
+        assert_eq!(range(7, 13), Some("\"x.py\"\x1b[0m"));
+        assert_eq!(range(8, 12), Some("x.py"));
+        assert_eq!(range(20, 21), Some("5\x1b[0m"));
+        assert_eq!(range(21, 21), None);
+        assert_eq!(range(20, 99), None);
+
+        let s = "\x1b[31mバー\x1b[0m.rs";
+        let stripped = StrippedText::new(s);
+        assert_eq!(stripped.original_range(0, 6), Some((5, 15)));
+        assert_eq!(stripped.original_range(3, 9), Some((8, 18)));
+    }
+
+    #[test]
+    fn test_hyperlink_marks() {
+        let s = "a \x1b]8;;file:///x\x1b\\link\x1b]8;;\x1b\\ b \x1b]8;id=1;http://h/?a;b\x07c";
+        let stripped = StrippedText::new(s);
+        assert_eq!(stripped.text, "a link b c");
+        let marks: Vec<_> = stripped
+            .hyperlink_marks
+            .iter()
+            .map(|mark| {
+                (
+                    mark.offset,
+                    &s[mark.sequence.0..mark.sequence.1],
+                    mark.opens,
+                )
+            })
+            .collect();
+        assert_eq!(
+            marks,
+            vec![
+                (2, "\x1b]8;;file:///x\x1b\\", true),
+                (6, "\x1b]8;;\x1b\\", false),
+                (9, "\x1b]8;id=1;http://h/?a;b\x07", true)
+            ]
+        );
+
+        // Other OSC and DCS strings are sequences, with their ESC \ terminators
+        let s = "a\x1b]2;t\x1b\\\x1bPq#0\x1b\\b";
+        let stripped = StrippedText::new(s);
+        assert_eq!(stripped.text, "ab");
+        assert!(stripped.hyperlink_marks.is_empty());
+        assert_eq!(stripped.sequences, vec![(1, 8), (8, 15)]);
+
+        assert_eq!(hyperlink_uri("\x1b]8;;file:///x\x1b"), Some("file:///x"));
+        assert_eq!(hyperlink_uri("\x1b]8;;file:///x\x1b\\"), Some("file:///x"));
+        assert_eq!(
+            hyperlink_uri("\x1b]8;id=1;http://h/?a;b\x07"),
+            Some("http://h/?a;b")
+        );
+        assert_eq!(hyperlink_uri("\x1b]8;;\x07"), Some(""));
+        assert_eq!(hyperlink_uri("\x1b]0;title\x07"), None);
+    }
+
     #[test]
     fn test_truncate_str() {
         assert_eq!(truncate_str("1", 1, ""), "1");
@@ -0,0 +1,258 @@
+// Escape sequences split between two reads of the output. The parser's state is kept from one
+// chunk to the next, and a chunk ending inside a sequence has that sequence held back and put in
+// front of the next chunk, so that the chunks passed on for transformation only ever contain
+// whole sequences. Without this, half an SGR or half an OSC 8 URL would be taken for text, and a
+// link could be inserted into the middle of a sequence.
+//
+// A sequence is held back only up to MAX_HELD_LEN bytes. A longer one, such as an image, is then
+// passed on as it arrives, as raw pieces that must not be transformed.
+
+use anstyle_parse::{DefaultCharAccumulator, Params, Parser, Perform};
+
+const MAX_HELD_LEN: usize = 64 * 1024;
+
+// Bytes of a long sequence kept for classifying the rest of it, e.g. by OSC number.
+const LONG_SEQUENCE_START_LEN: usize = 64;
+
+#[derive(Debug, PartialEq)]
+pub enum Piece {
+    // Text and whole sequences
+    Complete(String),
+    // Part of a sequence too long to be held back, and the first bytes of that sequence
+    Raw { text: String, sequence: String },
+}
+
+#[derive(Default)]
+pub struct StreamSplitter {
+    machine: Parser<DefaultCharAccumulator>,
+    // The incomplete sequence at the end of the output so far
+    held: String,
+    // The first bytes of the sequence being passed on raw, if one is
+    long_sequence: Option<String>,
+    // Inside a tmux passthrough string, `ESC P tmux; ... ESC \`, in which ESC is doubled, so
+    // that the parser would take the sequences inside for sequences of their own. Whether the
+    // last byte was an undoubled ESC.
+    passthrough: Option<bool>,
+}
+
+// What the parser did with a byte.
+#[derive(Default)]
+struct Events {
+    // Printed a character or ended a sequence
+    completed: bool,
+    // Started a tmux passthrough string
+    passthrough: bool,
+}
+
+impl StreamSplitter {
+    /// Split `chunk`, after anything held back from earlier chunks, into pieces to output in
+    /// order. An incomplete sequence at its end is held back.
+    pub fn push(&mut self, chunk: &str) -> Vec<Piece> {
+        let mut pieces = Vec::new();
+        let mut buffer = std::mem::take(&mut self.held);
+        let held_len = buffer.len();
+        buffer.push_str(chunk);
+
+        // Where the current sequence starts in `buffer`, if the parser is inside one
+        let mut sequence_start = (held_len > 0).then_some(0);
+        // Where the bytes not yet assigned to a piece start
+        let mut piece_start = 0;
+        for (i, &byte) in chunk.as_bytes().iter().enumerate() {
+            let pos = held_len + i;
+            let completed = match &mut self.passthrough {
+                Some(after_esc) => {
+                    let end = *after_esc && byte == b'\\';
+                    *after_esc = !*after_esc && byte == 0x1b;
+                    if end {
+                        self.passthrough = None;
+                        self.machine = Parser::default();
+                    }
+                    end
+                }
+                None => {
+                    let mut events = Events::default();
+                    self.machine.advance(&mut events, byte);
+                    if events.passthrough {
+                        self.passthrough = Some(false);
+                    }
+                    events.completed
+                }
+            };
+            // ESC also ends a string sequence such as OSC, but the ST it starts is kept with it
+            if completed && byte != 0x1b {
+                if let Some(sequence) = self.long_sequence.take() {
+                    // The end of a long sequence that was passed on raw
+                    let text = buffer[piece_start..=pos].to_string();
+                    pieces.push(Piece::Raw { text, sequence });
+                    piece_start = pos + 1;
+                }
+                sequence_start = None;
+            } else if sequence_start.is_none() && !is_ignored_in_text(byte) {
+                sequence_start = Some(pos);
+            }
+        }
+
+        match (&self.long_sequence, sequence_start) {
+            (Some(sequence), _) => {
+                if piece_start < buffer.len() {
+                    pieces.push(Piece::Raw {
+                        text: buffer[piece_start..].to_string(),
+                        sequence: sequence.clone(),
+                    });
+                }
+            }
+            (None, Some(start)) if buffer.len() - start > MAX_HELD_LEN => {
+                push_complete(&mut pieces, &buffer[piece_start..start]);
+                let text = &buffer[start..];
+                let mut start_len = LONG_SEQUENCE_START_LEN.min(text.len());
+                while !text.is_char_boundary(start_len) {
+                    start_len -= 1;
+                }
+                let sequence = text[..start_len].to_string();
+                self.long_sequence = Some(sequence.clone());
+                pieces.push(Piece::Raw {
+                    text: text.to_string(),
+                    sequence,
+                });
+            }
+            (None, Some(start)) => {
+                push_complete(&mut pieces, &buffer[piece_start..start]);
+                self.held = buffer[start..].to_string();
+            }
+            (None, None) => push_complete(&mut pieces, &buffer[piece_start..]),
+        }
+        pieces
+    }
+
+    /// Whatever is held back, at the end of the output.
+    pub fn finish(&mut self) -> String {
+        self.machine = Parser::default();
+        self.long_sequence = None;
+        self.passthrough = None;
+        std::mem::take(&mut self.held)
+    }
+}
+
+fn push_complete(pieces: &mut Vec<Piece>, text: &str) {
+    if !text.is_empty() {
+        pieces.push(Piece::Complete(text.to_string()));
+    }
+}
+
+// C0 controls other than ESC, and DEL, which do not end a sequence they occur in and do not start
+// one in text.
+fn is_ignored_in_text(byte: u8) -> bool {
+    (byte < 0x20 && byte != 0x1b) || byte == 0x7f
+}
+
+impl Perform for Events {
+    fn print(&mut self, _c: char) {
+        self.completed = true;
+    }
+
+    fn csi_dispatch(&mut self, _params: &Params, _intermediates: &[u8], _ignore: bool, _c: u8) {
+        self.completed = true;
+    }
+
+    fn esc_dispatch(&mut self, _intermediates: &[u8], _ignore: bool, _byte: u8) {
+        self.completed = true;
+    }
+
+    fn osc_dispatch(&mut self, _params: &[&[u8]], _bell_terminated: bool) {
+        self.completed = true;
+    }
+
+    fn hook(&mut self, params: &Params, intermediates: &[u8], _ignore: bool, byte: u8) {
+        // `ESC P t`, as the parser takes the `t` of `tmux;` for the end of the DCS header. A DCS
+        // without parameters is given a single 0.
+        self.passthrough =
+            byte == b't' && intermediates.is_empty() && params.iter().all(|param| param == [0]);
+    }
+
+    fn unhook(&mut self) {
+        self.completed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete(s: &str) -> Piece {
+        Piece::Complete(s.to_string())
+    }
+
+    fn raw(text: &str, sequence: &str) -> Piece {
+        Piece::Raw {
+            text: text.to_string(),
+            sequence: sequence[..LONG_SEQUENCE_START_LEN].to_string(),
+        }
+    }
+
+    #[test]
+    fn test_split_sequences() {
+        let mut splitter = StreamSplitter::default();
+        assert_eq!(
+            splitter.push("src/a.rs:1 \x1b[3"),
+            vec![complete("src/a.rs:1 ")]
+        );
+        assert_eq!(
+            splitter.push("1mred\x1b[0m\n"),
+            vec![complete("\x1b[31mred\x1b[0m\n")]
+        );
+
+        // An OSC 8 URL in three pieces, terminated by ESC \ split after the ESC
+        assert_eq!(splitter.push("a \x1b]8;;file:///s"), vec![complete("a ")]);
+        assert_eq!(splitter.push("rc/x.rs\x1b"), vec![]);
+        assert_eq!(
+            splitter.push("\\x\x1b]8;;\x07\n"),
+            vec![complete("\x1b]8;;file:///src/x.rs\x1b\\x\x1b]8;;\x07\n")]
+        );
+
+        // Controls inside a sequence do not end it
+        assert_eq!(splitter.push("\x1b[1\r"), vec![]);
+        assert_eq!(splitter.push("m!"), vec![complete("\x1b[1\rm!")]);
+        assert_eq!(splitter.push("\r\n"), vec![complete("\r\n")]);
+
+        // ESC is doubled inside tmux passthrough, and does not end it
+        assert_eq!(
+            splitter.push("a\x1bPtmux;\x1b\x1b]8;;x\x07src/a.rs:1\x1b\x1b[0m"),
+            vec![complete("a")]
+        );
+        assert_eq!(
+            splitter.push("\x1b\x1b\\\x1b\\b"),
+            vec![complete(
+                "\x1bPtmux;\x1b\x1b]8;;x\x07src/a.rs:1\x1b\x1b[0m\x1b\x1b\\\x1b\\b"
+            )]
+        );
+
+        // Held back until the end of the output
+        assert_eq!(splitter.push("done\x1b]0;tit"), vec![complete("done")]);
+        assert_eq!(splitter.finish(), "\x1b]0;tit");
+        assert_eq!(splitter.push("x\n"), vec![complete("x\n")]);
+    }
+
+    #[test]
+    fn test_split_long_sequence() {
+        let mut splitter = StreamSplitter::default();
+        let payload = "A".repeat(MAX_HELD_LEN);
+        let start = format!("\x1b_Gf=100;{}", payload);
+        assert_eq!(
+            splitter.push(&format!("text {}", start)),
+            vec![complete("text "), raw(&start, &start)]
+        );
+        assert_eq!(splitter.push("AAAA"), vec![raw("AAAA", &start)]);
+        assert_eq!(
+            splitter.push("AA\x1b\\ src/a.rs:1\n"),
+            vec![raw("AA\x1b\\", &start), complete(" src/a.rs:1\n")]
+        );
+
+        // An OSC ending in ESC \ is passed on raw up to the backslash
+        let start = format!("\x1b]52;c;{}", payload);
+        assert_eq!(splitter.push(&start), vec![raw(&start, &start)]);
+        assert_eq!(
+            splitter.push("A\x1b\\x\n"),
+            vec![raw("A\x1b\\", &start), complete("x\n")]
+        );
+    }
+}
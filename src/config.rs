@@ -0,0 +1,265 @@
+// User configuration, read once at startup from TOML files:
+//
+// 1. ~/.config/xolmis/config.toml (or $XDG_CONFIG_HOME/xolmis/config.toml)
+// 2. .xolmis.toml in the working directory or the nearest directory above it
+//
+// Settings in the project file take precedence over the global file; tables are merged key by
+// key. The sections in GLOBAL_ONLY_SECTIONS guard against untrusted output or choose what opens
+// links, and a project file, which comes with whatever repository is checked out, may not set
+// them. Relative paths in either file are relative to the project file's directory if there is
+// one, and to the working directory otherwise.
+//
+// Example:
+//
+// notebook = "analysis.ipynb"
+//
+// search_roots = ["~/src/shared"]
+//
+// [path_map]
+// "/app" = "~/src/service"
+//
+// [filter]
+// exclude = ["target", "node_modules", ".venv"]
+// gitignore = true
+//
+// [escape_filter]
+// clipboard = "drop"
+// title_report = "drop"
+//
+// [existing_links]
+// policy = "annotate"
+// rewrite_file_uris = true
+//
+// [line_check]
+// mode = "clamp"
+// rules = { LatexLineMarker = "off" }
+//
+// [link_ids]
+// enabled = true
+// shared_rules = ["PythonTraceback", "PythonCaretLine"]
+//
+// [links]
+// file = "vscode://file{path}[:{line}[:{column}]]"
+// directory = "file://{path}"
+// extensions = { svg = "file://{path}" }
+
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::escape_filter::EscapeFilterConfig;
+use crate::existing_links::ExistingLinksConfig;
+use crate::line_check::LineCheckConfig;
+use crate::link_ids::LinkIdConfig;
+use crate::path_filter::FilterConfig;
+use crate::resolve::{expand_config_path, PathMap};
+use crate::template::LinkTemplates;
+
+pub const PROJECT_CONFIG_FILE_NAME: &str = ".xolmis.toml";
+
+const GLOBAL_ONLY_SECTIONS: &[&str] = &["escape_filter", "existing_links", "links"];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // The notebook that IPython `Cell In[N], line M` frames refer to.
+    pub notebook: Option<PathBuf>,
+    // Prefixes of paths printed inside containers, and where they live on this machine.
+    pub path_map: PathMap,
+    // Further directories that relative paths are looked up in, after the cwd and the git work
+    // tree root.
+    pub search_roots: Vec<PathBuf>,
+    // URL templates for link targets (see `template::LinkTemplates`)
+    pub links: LinkTemplates,
+    // Which risky escape sequences to remove from the output (see `escape_filter`)
+    pub escape_filter: EscapeFilterConfig,
+    // What to do with hyperlinks already in the output
+    pub existing_links: ExistingLinksConfig,
+    // Which paths may be linked (see `path_filter::PathFilter`)
+    pub filter: FilterConfig,
+    // Whether to check that linked lines exist (see `line_check::LineCheckConfig`)
+    pub line_check: LineCheckConfig,
+    // Whether to give links `id=` parameters (see `link_ids`)
+    pub link_ids: LinkIdConfig,
+}
+
+impl Config {
+    /// Load the global and project configuration files for a session started in `cwd`. Errors
+    /// are reported on stderr and the offending file is ignored.
+    pub fn load(cwd: &Path) -> Self {
+        Self::load_files(global_config_file().as_deref(), cwd)
+    }
+
+    // `load`, with the global configuration file given.
+    fn load_files(global_file: Option<&Path>, cwd: &Path) -> Self {
+        let project_file = find_project_config_file(cwd);
+        let base_dir = project_file
+            .as_deref()
+            .and_then(Path::parent)
+            .unwrap_or(cwd)
+            .to_path_buf();
+
+        let mut table = toml::Table::new();
+        if let Some(file_table) = global_file.and_then(read_table) {
+            merge_tables(&mut table, file_table);
+        }
+        if let Some(path) = project_file.as_deref() {
+            if let Some(mut file_table) = read_table(path) {
+                remove_global_only_sections(path, &mut file_table);
+                merge_tables(&mut table, file_table);
+            }
+        }
+        match Self::deserialize(table) {
+            Ok(mut config) => {
+                config.line_check.check_rule_names();
+                config.link_ids.check_rule_names();
+                config.with_base_dir(&base_dir)
+            }
+            Err(e) => {
+                eprintln!("xolmis: ignoring configuration: {}", e);
+                Self::default()
+            }
+        }
+    }
+
+    // Make relative paths absolute.
+    fn with_base_dir(mut self, base_dir: &Path) -> Self {
+        self.notebook = self
+            .notebook
+            .and_then(|path| expand_config_path(&path, base_dir));
+        self.path_map = self.path_map.with_base_dir(base_dir);
+        self.search_roots = self
+            .search_roots
+            .iter()
+            .filter_map(|path| expand_config_path(path, base_dir))
+            .collect();
+        self
+    }
+}
+
+fn global_config_file() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join("xolmis").join("config.toml"))
+}
+
+fn find_project_config_file(cwd: &Path) -> Option<PathBuf> {
+    cwd.ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+fn read_table(path: &Path) -> Option<toml::Table> {
+    let contents = fs::read_to_string(path).ok()?;
+    match contents.parse::<toml::Table>() {
+        Ok(table) => Some(table),
+        Err(e) => {
+            eprintln!("xolmis: ignoring {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+fn remove_global_only_sections(path: &Path, table: &mut toml::Table) {
+    for section in GLOBAL_ONLY_SECTIONS {
+        if table.remove(*section).is_some() {
+            eprintln!(
+                "xolmis: ignoring [{}] in {}: only the global configuration may set it",
+                section,
+                path.display()
+            );
+        }
+    }
+}
+
+// Merge `overrides` into `base`, recursing into tables that both define.
+fn merge_tables(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(table)) => {
+                merge_tables(base_table, table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::escape_filter::FilterAction;
+    use crate::existing_links::LinkPolicy;
+    use crate::template::{FileKind, Position};
+
+    #[test]
+    fn test_merge_tables() {
+        let mut base: toml::Table = "a = 1\n[t]\nx = 1\ny = 2".parse().unwrap();
+        let overrides: toml::Table = "b = 2\n[t]\ny = 3".parse().unwrap();
+        merge_tables(&mut base, overrides);
+        let expected: toml::Table = "a = 1\nb = 2\n[t]\nx = 1\ny = 3".parse().unwrap();
+        assert_eq!(base, expected);
+    }
+
+    #[test]
+    fn test_load_project_config() {
+        let dir = env::temp_dir().join(format!("xolmis_config_{}", std::process::id()));
+        let subdir = dir.join("sub");
+        fs::create_dir_all(&subdir).unwrap();
+        fs::write(
+            dir.join(PROJECT_CONFIG_FILE_NAME),
+            "notebook = \"nb/analysis.ipynb\"\n[path_map]\n\"/app\" = \"service\"\n",
+        )
+        .unwrap();
+        let config = Config::load_files(None, &subdir);
+        assert_eq!(config.notebook, Some(dir.join("nb/analysis.ipynb")));
+        assert_eq!(
+            config.path_map.apply(Path::new("/app/main.py")),
+            dir.join("service/main.py")
+        );
+
+        // Settings from the global file, with `~` expanded
+        let global_file = dir.join("config.toml");
+        fs::write(&global_file, "notebook = \"~/nb/x.ipynb\"\n").unwrap();
+        fs::remove_file(dir.join(PROJECT_CONFIG_FILE_NAME)).unwrap();
+        let config = Config::load_files(Some(&global_file), &subdir);
+        let home = PathBuf::from(env::var("HOME").unwrap());
+        assert_eq!(config.notebook, Some(home.join("nb/x.ipynb")));
+
+        // The project file cannot loosen the escape filter
+        fs::write(&global_file, "[escape_filter]\nclipboard = \"drop\"\n").unwrap();
+        fs::write(
+            dir.join(PROJECT_CONFIG_FILE_NAME),
+            "[escape_filter]\nclipboard = \"allow\"\n",
+        )
+        .unwrap();
+        let config = Config::load_files(Some(&global_file), &subdir);
+        assert_eq!(config.escape_filter.clipboard, FilterAction::Drop);
+
+        // Nor let more existing links through, or change the link templates
+        fs::write(
+            &global_file,
+            "[existing_links]\npolicy = \"allowlist\"\nallowed_hosts = [\"github.com\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join(PROJECT_CONFIG_FILE_NAME),
+            "[existing_links]\npolicy = \"allow\"\nallowed_hosts = [\"evil.example\"]\n\
+             [links]\nfile = \"evil://{path}\"\n",
+        )
+        .unwrap();
+        let config = Config::load_files(Some(&global_file), &subdir);
+        assert_eq!(config.existing_links.policy, LinkPolicy::Allowlist);
+        assert_eq!(config.existing_links.allowed_hosts, ["github.com"]);
+        assert_eq!(
+            config
+                .links
+                .url(Path::new("/x.py"), FileKind::Text, Position::default()),
+            LinkTemplates::default().url(Path::new("/x.py"), FileKind::Text, Position::default())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+// Build tools announce the directory they are working in, and the relative paths they print
+// afterwards are relative to that directory rather than to the shell's cwd:
+//
+// make[2]: Entering directory '/src/proj/lib'
+// make[2]: Leaving directory '/src/proj/lib'
+// ninja: Entering directory `build'
+//    Compiling foo v0.1.0 (/src/ws/foo)
+// (cd /home/u/.cache/bazel/_bazel_u/1a2b/execroot/_main && exec env - ...
+//
+// `DirectoryStack` follows these announcements line by line so that `transform` can fall back
+// to the innermost announced directory when a path does not resolve against the cwd.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+lazy_static! {
+    // GNU make (and gmake, mingw32-make, ...), with an optional recursion level.
+    static ref MAKE_DIRECTORY_REGEX: Regex = Regex::new(
+        r#"^\S*make(?:\[\d+\])?: (?P<action>Entering|Leaving) directory [`'"](?P<dir>.+)['"]$"#
+    )
+    .unwrap();
+    // ninja, and therefore meson/cmake builds driven by ninja. There is no "Leaving" message.
+    static ref NINJA_DIRECTORY_REGEX: Regex =
+        Regex::new(r#"^ninja: Entering directory [`'"](?P<dir>.+)['"]$"#).unwrap();
+    // cargo only prints a directory for path dependencies and workspace members.
+    static ref CARGO_DIRECTORY_REGEX: Regex = Regex::new(
+        r"^\s*(?:Compiling|Checking|Documenting) \S+ v\S+ \((?P<dir>/[^)]+)\)$"
+    )
+    .unwrap();
+    // bazel with --verbose_failures prints the failing command, prefixed by a cd into the execroot.
+    static ref BAZEL_DIRECTORY_REGEX: Regex =
+        Regex::new(r"^\s*\(cd (?P<dir>/\S+) && exec ").unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Source {
+    Make,
+    Ninja,
+    Cargo,
+    Bazel,
+}
+
+#[derive(Debug, Default)]
+pub struct DirectoryStack {
+    entries: Vec<(Source, PathBuf)>,
+}
+
+impl DirectoryStack {
+    /// Update the stack from one line of (ANSI-stripped) output. Relative directories are
+    /// resolved against the innermost directory announced so far, or else against `cwd`.
+    pub fn observe_line(&mut self, line: &str, cwd: &Path) {
+        if let Some(caps) = MAKE_DIRECTORY_REGEX.captures(line) {
+            let dir = self.absolute(&caps["dir"], cwd);
+            if &caps["action"] == "Entering" {
+                self.entries.push((Source::Make, dir));
+            } else if let Some(index) = self
+                .entries
+                .iter()
+                .rposition(|(source, d)| *source == Source::Make && *d == dir)
+            {
+                // Anything announced inside the make invocation that is leaving ends with it.
+                self.entries.truncate(index);
+            }
+        } else if let Some(caps) = NINJA_DIRECTORY_REGEX.captures(line) {
+            self.replace(Source::Ninja, &caps["dir"], cwd);
+        } else if let Some(caps) = CARGO_DIRECTORY_REGEX.captures(line) {
+            self.replace(Source::Cargo, &caps["dir"], cwd);
+        } else if let Some(caps) = BAZEL_DIRECTORY_REGEX.captures(line) {
+            self.replace(Source::Bazel, &caps["dir"], cwd);
+        }
+    }
+
+    /// The most recently announced directory that is still in effect.
+    pub fn innermost(&self) -> Option<&Path> {
+        self.entries.last().map(|(_, dir)| dir.as_path())
+    }
+
+    // Tools without a "Leaving" message announce a new directory in place of their previous one.
+    fn replace(&mut self, source: Source, dir: &str, cwd: &Path) {
+        self.entries.retain(|(s, _)| *s != source);
+        let dir = self.absolute(dir, cwd);
+        self.entries.push((source, dir));
+    }
+
+    fn absolute(&self, dir: &str, cwd: &Path) -> PathBuf {
+        let base = self.innermost().unwrap_or(cwd);
+        base.join(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_entering_and_leaving() {
+        let cwd = Path::new("/src/proj");
+        let mut stack = DirectoryStack::default();
+        stack.observe_line("make[1]: Entering directory '/src/proj/lib'", cwd);
+        assert_eq!(stack.innermost(), Some(Path::new("/src/proj/lib")));
+        stack.observe_line("make[2]: Entering directory '/src/proj/lib/sub'", cwd);
+        assert_eq!(stack.innermost(), Some(Path::new("/src/proj/lib/sub")));
+        stack.observe_line("make[2]: Leaving directory '/src/proj/lib/sub'", cwd);
+        assert_eq!(stack.innermost(), Some(Path::new("/src/proj/lib")));
+        stack.observe_line("make[1]: Leaving directory '/src/proj/lib'", cwd);
+        assert_eq!(stack.innermost(), None);
+    }
+
+    #[test]
+    fn test_make_leaving_unknown_directory_is_ignored() {
+        let cwd = Path::new("/src/proj");
+        let mut stack = DirectoryStack::default();
+        stack.observe_line("make: Entering directory `/src/proj/lib'", cwd);
+        stack.observe_line("make: Leaving directory '/elsewhere'", cwd);
+        assert_eq!(stack.innermost(), Some(Path::new("/src/proj/lib")));
+    }
+
+    #[test]
+    fn test_ninja_relative_directory() {
+        let cwd = Path::new("/src/proj");
+        let mut stack = DirectoryStack::default();
+        stack.observe_line("ninja: Entering directory `build'", cwd);
+        assert_eq!(stack.innermost(), Some(Path::new("/src/proj/build")));
+        // A second ninja run replaces the first rather than nesting inside it.
+        stack.observe_line("ninja: Entering directory 'out'", cwd);
+        assert_eq!(stack.innermost(), Some(Path::new("/src/proj/out")));
+    }
+
+    #[test]
+    fn test_cargo_and_bazel_directories() {
+        let cwd = Path::new("/src/ws");
+        let mut stack = DirectoryStack::default();
+        stack.observe_line("   Compiling foo v0.1.0 (/src/ws/foo)", cwd);
+        assert_eq!(stack.innermost(), Some(Path::new("/src/ws/foo")));
+        stack.observe_line("   Compiling regex v1.10.0", cwd);
+        assert_eq!(stack.innermost(), Some(Path::new("/src/ws/foo")));
+        stack.observe_line("    Checking bar v0.2.0 (/src/ws/bar)", cwd);
+        assert_eq!(stack.innermost(), Some(Path::new("/src/ws/bar")));
+        stack.observe_line(
+            "  (cd /home/u/.cache/bazel/_bazel_u/1a2b/execroot/_main && exec env - PATH=/bin \\",
+            cwd,
+        );
+        assert_eq!(
+            stack.innermost(),
+            Some(Path::new(
+                "/home/u/.cache/bazel/_bazel_u/1a2b/execroot/_main"
+            ))
+        );
+    }
+}
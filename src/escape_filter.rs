@@ -0,0 +1,175 @@
+// An optional firewall for escape sequences in the output of the child, so that untrusted output
+// such as a log file or a remote server's response can be displayed safely. Each kind of risky
+// sequence can be allowed, dropped, or shown as visible text instead of being acted on:
+//
+// - clipboard: OSC 52, which writes the clipboard (or with `?` asks for its contents)
+// - title_report: CSI 20 t and CSI 21 t, which make the terminal type the icon label or window
+//   title into the input, where a crafted title may be run as a command
+// - decrqss: DCS $ q, which likewise makes the terminal report its settings into the input
+// - font: OSC 50
+// - palette: OSC 4, 5, 10-19 and their resets 104, 105, 110-119, which change or query colours
+//
+// Sequences split between two reads of the output are held back until they are whole (see
+// `ansi::stream`). One too long for that is filtered piece by piece as it arrives.
+//
+// Unlike everything else, the filter looks at DCS strings and tmux passthrough strings, which are
+// otherwise passed on untouched. This is deliberate: a DCS string is only matched against
+// `$ q`, so a sixel image is never altered, and the sequence inside a passthrough string reaches
+// the outer terminal just as one outside it would, so it is judged by what it carries.
+//
+// Only the 7-bit `ESC` introducers are recognised. The 8-bit C1 forms (U+0090 DCS, U+009B CSI,
+// U+009D OSC) are out of scope: the parser takes them for text, and most terminals ignore them
+// in UTF-8 output, but a terminal that acts on them is not protected by the filter.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+
+lazy_static! {
+    static ref OSC_NUMBER_REGEX: Regex =
+        Regex::new(r"^\x1b\](?P<number>\d+)(?:;|\x07|\x1b|$)").unwrap();
+    static ref TITLE_REPORT_REGEX: Regex = Regex::new(r"^\x1b\[2[01]t$").unwrap();
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    #[default]
+    Allow,
+    Drop,
+    // Replace the sequence with a printable rendering of it, e.g. `^[]52;c;...^G`
+    Show,
+}
+
+/// The `[escape_filter]` section of the configuration.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EscapeFilterConfig {
+    pub clipboard: FilterAction,
+    pub title_report: FilterAction,
+    pub decrqss: FilterAction,
+    pub font: FilterAction,
+    pub palette: FilterAction,
+}
+
+impl EscapeFilterConfig {
+    fn is_enabled(&self) -> bool {
+        [
+            self.clipboard,
+            self.title_report,
+            self.decrqss,
+            self.font,
+            self.palette,
+        ]
+        .iter()
+        .any(|&action| action != FilterAction::Allow)
+    }
+
+    /// What to output instead of the escape sequence `sequence`, or None to output it as it is.
+    pub fn replacement(&self, sequence: &str) -> Option<String> {
+        self.part_replacement(sequence, sequence)
+    }
+
+    /// What to output instead of `part` of a sequence starting with `sequence_start`, for a
+    /// sequence that arrives in pieces.
+    pub fn part_replacement(&self, sequence_start: &str, part: &str) -> Option<String> {
+        if !self.is_enabled() {
+            return None;
+        }
+        match self.action(sequence_start) {
+            FilterAction::Allow => None,
+            FilterAction::Drop => Some(String::new()),
+            FilterAction::Show => Some(printable(part)),
+        }
+    }
+
+    fn action(&self, sequence: &str) -> FilterAction {
+        // A sequence passed through tmux to the terminal outside it is judged by what it carries
+        if let Some(inner) = sequence.strip_prefix("\x1bPtmux;") {
+            return self.action(&inner.replace("\x1b\x1b", "\x1b"));
+        }
+        if let Some(caps) = OSC_NUMBER_REGEX.captures(sequence) {
+            return match caps["number"].parse::<u32>().unwrap_or(u32::MAX) {
+                52 => self.clipboard,
+                50 => self.font,
+                4 | 5 | 10..=19 | 104 | 105 | 110..=119 => self.palette,
+                _ => FilterAction::Allow,
+            };
+        }
+        if TITLE_REPORT_REGEX.is_match(sequence) {
+            return self.title_report;
+        }
+        if sequence.starts_with("\x1bP$q") {
+            return self.decrqss;
+        }
+        FilterAction::Allow
+    }
+}
+
+// Caret notation for control characters, so that the sequence is displayed rather than obeyed.
+fn printable(sequence: &str) -> String {
+    let mut shown = String::with_capacity(sequence.len() + 4);
+    for c in sequence.chars() {
+        match c {
+            '\x00'..='\x1f' => {
+                shown.push('^');
+                shown.push((c as u8 + b'@') as char);
+            }
+            '\x7f' => shown.push_str("^?"),
+            c if c.is_control() => shown.extend(c.escape_unicode()),
+            c => shown.push(c),
+        }
+    }
+    shown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_filter() {
+        let config: EscapeFilterConfig = toml::from_str(
+            "clipboard = \"drop\"\ntitle_report = \"drop\"\ndecrqss = \"show\"\npalette = \"drop\"\n",
+        )
+        .unwrap();
+        let replacement = |sequence: &str| config.replacement(sequence);
+        assert_eq!(replacement("\x1b]52;c;aGVsbG8=\x07").as_deref(), Some(""));
+        assert_eq!(replacement("\x1b]52;c;?\x1b\\").as_deref(), Some(""));
+        assert_eq!(replacement("\x1b[21t").as_deref(), Some(""));
+        assert_eq!(replacement("\x1b[20t").as_deref(), Some(""));
+        assert_eq!(replacement("\x1b[8;24;80t"), None);
+        assert_eq!(replacement("\x1bP$qm\x1b\\").as_deref(), Some("^[P$qm^[\\"));
+        assert_eq!(
+            replacement("\x1b]4;1;rgb:ff/00/00\x07").as_deref(),
+            Some("")
+        );
+        assert_eq!(replacement("\x1b]11;?\x07").as_deref(), Some(""));
+        assert_eq!(replacement("\x1b]104\x07").as_deref(), Some(""));
+        assert_eq!(
+            replacement("\x1bPtmux;\x1b\x1b]52;c;aGk=\x07\x1b\\").as_deref(),
+            Some("")
+        );
+        assert_eq!(replacement("\x1bPtmux;\x1b\x1b]2;title\x07\x1b\\"), None);
+        // Allowed by default
+        assert_eq!(replacement("\x1b]50;fixed\x07"), None);
+        // Not risky
+        assert_eq!(replacement("\x1b]2;my title\x07"), None);
+        assert_eq!(replacement("\x1b]521;x\x07"), None);
+        assert_eq!(replacement("\x1b[K"), None);
+
+        assert_eq!(
+            EscapeFilterConfig::default().replacement("\x1b]52;c;aGVsbG8=\x07"),
+            None
+        );
+
+        // A long sequence in pieces
+        let start = "\x1b]52;c;aGVs";
+        assert_eq!(config.part_replacement(start, "bG8=").as_deref(), Some(""));
+        assert_eq!(config.part_replacement("\x1b]2;ti", "tle"), None);
+        assert_eq!(
+            config.part_replacement("\x1bP$qm", "\x1b\\").as_deref(),
+            Some("^[\\")
+        );
+    }
+}
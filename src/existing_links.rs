@@ -0,0 +1,244 @@
+// Hyperlinks already present in the output. `ls --hyperlink`, gcc, delta and systemd tools emit
+// OSC 8 links with `file://host/path` targets, which many terminals can only hand to a file
+// manager. Their targets can be rewritten through the configured URL templates so that they open
+// in the editor like our own links, keeping any line number they carry.
+//
+// Output from a remote server, `curl` or a log file may also contain links whose visible text
+// does not match their target. A policy decides whether such links are kept, stripped (leaving
+// their text), kept only for allowed schemes and hosts, or kept with their target shown after
+// them.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+use crate::template::Position;
+
+lazy_static! {
+    static ref HOSTNAME: Option<String> = nix::unistd::gethostname()
+        .ok()
+        .and_then(|name| name.into_string().ok());
+    // `#L12`, `#L12C3`, `#L12-L20` or `#12`
+    static ref LINE_FRAGMENT_REGEX: Regex = Regex::new(
+        r"^L?(?P<line>\d+)(?:C(?P<column>\d+))?(?:-L?(?P<end_line>\d+)(?:C(?P<end_column>\d+))?)?$"
+    )
+    .unwrap();
+    // `:12` or `:12:3` at the end of the path
+    static ref LINE_SUFFIX_REGEX: Regex =
+        Regex::new(r":(?P<line>\d+)(?::(?P<column>\d+))?$").unwrap();
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkPolicy {
+    #[default]
+    Allow,
+    Strip,
+    // Keep only links with an allowed scheme and host
+    Allowlist,
+    // Show the target after the link text
+    Annotate,
+}
+
+/// The `[existing_links]` section of the configuration.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExistingLinksConfig {
+    pub policy: LinkPolicy,
+    // For the allowlist policy, e.g. "https"
+    pub allowed_schemes: Vec<String>,
+    // For the allowlist policy, e.g. "github.com" or "*.example.com". URIs without a host, such
+    // as `file:///x`, need only an allowed scheme.
+    pub allowed_hosts: Vec<String>,
+    // Rewrite `file:` URIs on this host through the `[links]` templates
+    pub rewrite_file_uris: bool,
+}
+
+impl ExistingLinksConfig {
+    /// Whether the policy keeps a link to `uri`.
+    pub fn keeps(&self, uri: &str) -> bool {
+        match self.policy {
+            LinkPolicy::Allow | LinkPolicy::Annotate => true,
+            LinkPolicy::Strip => false,
+            LinkPolicy::Allowlist => self.is_allowed(uri),
+        }
+    }
+
+    fn is_allowed(&self, uri: &str) -> bool {
+        let Some((scheme, host)) = scheme_and_host(uri) else {
+            return false;
+        };
+        let scheme_allowed = self
+            .allowed_schemes
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(scheme));
+        let host_allowed = host.is_empty()
+            || self.allowed_hosts.iter().any(|allowed| {
+                let allowed = allowed.to_ascii_lowercase();
+                match allowed.strip_prefix("*.") {
+                    Some(domain) => host
+                        .strip_suffix(domain)
+                        .is_some_and(|sub| sub.ends_with('.')),
+                    None => host == allowed,
+                }
+            });
+        scheme_allowed && host_allowed
+    }
+}
+
+/// Text to show after a link to `uri`, with any control characters removed.
+pub fn annotation(uri: &str) -> String {
+    let uri: String = uri.chars().filter(|c| !c.is_control()).collect();
+    format!(" <{}>", uri)
+}
+
+// The scheme of a URI and its host, in lower case, which is empty if it has none. Any user info
+// is skipped, so that `https://github.com@evil.example/` is on evil.example.
+fn scheme_and_host(uri: &str) -> Option<(&str, String)> {
+    let (scheme, rest) = uri.split_once(':')?;
+    let valid_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    if !valid_scheme {
+        return None;
+    }
+    let Some(rest) = rest.strip_prefix("//") else {
+        return Some((scheme, String::new()));
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host_port = authority.rsplit('@').next().unwrap_or_default();
+    let host = match host_port.strip_prefix('[') {
+        // An IPv6 address
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host_port.split(':').next().unwrap_or_default(),
+    };
+    Some((scheme, host.to_ascii_lowercase()))
+}
+
+/// The path and position that a `file:` URI on this host refers to. The position comes from a
+/// `#L12`-style fragment, or a `:12[:3]` suffix on the path. None for other URIs, including
+/// `file:` URIs naming another host.
+pub fn parse_file_uri(uri: &str) -> Option<(PathBuf, Position)> {
+    let rest = uri.strip_prefix("file://")?;
+    let (host, rest) = rest.split_at(rest.find('/')?);
+    if !(host.is_empty() || host == "localhost" || HOSTNAME.as_deref() == Some(host)) {
+        return None;
+    }
+    let (rest, fragment) = match rest.split_once('#') {
+        Some((rest, fragment)) => (rest, Some(fragment)),
+        None => (rest, None),
+    };
+    // A query has no meaning for a file
+    let rest = rest.split_once('?').map_or(rest, |(rest, _)| rest);
+    let mut path = percent_decode(rest)?;
+
+    let number = |caps: &regex::Captures, name| caps.name(name)?.as_str().parse().ok();
+    let mut position = Position::default();
+    if let Some(caps) = fragment.and_then(|f| LINE_FRAGMENT_REGEX.captures(f)) {
+        position = Position {
+            line: number(&caps, "line"),
+            column: number(&caps, "column"),
+            end_line: number(&caps, "end_line"),
+            end_column: number(&caps, "end_column"),
+        };
+    } else if let Some(caps) = LINE_SUFFIX_REGEX.captures(&path) {
+        position.line = number(&caps, "line");
+        position.column = number(&caps, "column");
+        path.truncate(caps.get(0).unwrap().start());
+    }
+    Some((PathBuf::from(path), position))
+}
+
+// Decode `%XX` escapes. None if an escape is malformed or the result is not UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(uri: &str) -> Option<(String, Position)> {
+        parse_file_uri(uri).map(|(path, position)| (path.to_string_lossy().into_owned(), position))
+    }
+
+    fn at(line: Option<u32>, column: Option<u32>, end_line: Option<u32>) -> Position {
+        Position {
+            line,
+            column,
+            end_line,
+            end_column: None,
+        }
+    }
+
+    #[test]
+    fn test_link_policy() {
+        let config: ExistingLinksConfig = toml::from_str(
+            "policy = \"allowlist\"\nallowed_schemes = [\"https\", \"file\"]\nallowed_hosts = [\"github.com\", \"*.example.com\"]\n",
+        )
+        .unwrap();
+        assert!(config.keeps("https://github.com/a/b"));
+        assert!(config.keeps("HTTPS://GitHub.com:443/a"));
+        assert!(config.keeps("https://docs.example.com/x"));
+        assert!(config.keeps("file:///home/me/x.rs"));
+        assert!(!config.keeps("https://example.com/x"));
+        assert!(!config.keeps("https://notexample.com/x"));
+        assert!(!config.keeps("https://github.com@evil.example/x"));
+        assert!(!config.keeps("http://github.com/a/b"));
+        assert!(!config.keeps("javascript:alert(1)"));
+        assert!(!config.keeps("not a uri"));
+
+        assert!(ExistingLinksConfig::default().keeps("http://evil.example/"));
+        assert_eq!(
+            annotation("https://evil.example/\u{9b}x"),
+            " <https://evil.example/x>"
+        );
+    }
+
+    #[test]
+    fn test_parse_file_uri() {
+        assert_eq!(
+            parse("file:///home/me/My%20Docs/notes.md"),
+            Some((
+                "/home/me/My Docs/notes.md".to_string(),
+                at(None, None, None)
+            ))
+        );
+        assert_eq!(
+            parse("file://localhost/src/main.rs#L12"),
+            Some(("/src/main.rs".to_string(), at(Some(12), None, None)))
+        );
+        assert_eq!(
+            parse("file:///src/main.rs#L12C3-L20"),
+            Some(("/src/main.rs".to_string(), at(Some(12), Some(3), Some(20))))
+        );
+        assert_eq!(
+            parse("file:///src/main.rs:12:3"),
+            Some(("/src/main.rs".to_string(), at(Some(12), Some(3), None)))
+        );
+        if let Some(hostname) = HOSTNAME.as_deref() {
+            let uri = format!("file://{}/etc/hosts", hostname);
+            assert_eq!(
+                parse(&uri),
+                Some(("/etc/hosts".to_string(), at(None, None, None)))
+            );
+        }
+        assert_eq!(parse("file://elsewhere.invalid/etc/hosts"), None);
+        assert_eq!(parse("https://example.com/src/main.rs"), None);
+        assert_eq!(parse("file:///bad%2"), None);
+    }
+}
@@ -0,0 +1,355 @@
+// An index of the files in the project, so that tools which print only a basename
+// (`handler.go:45`) or an elided path (`…/models/user.rb:12`) can still be linked when the name
+// identifies a single file.
+//
+// The index is built on a background thread by walking the git work tree, skipping hidden files
+// and those ignored by .gitignore, and is kept current by watching each indexed directory. It
+// stops growing at MAX_INDEXED_FILES files, so a huge monorepo costs bounded memory and watches.
+
+use ignore::WalkBuilder;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+const MAX_INDEXED_FILES: usize = 200_000;
+
+// How long to let a burst of filesystem events, e.g. from a checkout, settle before rescanning.
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Default)]
+struct Index {
+    // Names of the files directly in each indexed directory, relative to the root
+    files_by_dir: HashMap<PathBuf, Vec<OsString>>,
+    // Directories containing a file with each name
+    dirs_by_name: HashMap<OsString, Vec<PathBuf>>,
+    file_count: usize,
+}
+
+impl Index {
+    fn is_full(&self) -> bool {
+        self.file_count >= MAX_INDEXED_FILES
+    }
+
+    fn add_dir(&mut self, dir: PathBuf) {
+        self.files_by_dir.entry(dir).or_default();
+    }
+
+    fn add_file(&mut self, dir: &Path, name: OsString) {
+        if self.is_full() {
+            return;
+        }
+        self.dirs_by_name
+            .entry(name.clone())
+            .or_default()
+            .push(dir.to_path_buf());
+        self.files_by_dir
+            .entry(dir.to_path_buf())
+            .or_default()
+            .push(name);
+        self.file_count += 1;
+    }
+
+    // Forget the files directly in `dir`, keeping the directory itself.
+    fn clear_dir(&mut self, dir: &Path) {
+        let Some(names) = self.files_by_dir.get_mut(dir) else {
+            return;
+        };
+        for name in names.drain(..) {
+            if let Some(dirs) = self.dirs_by_name.get_mut(&name) {
+                dirs.retain(|d| d != dir);
+                if dirs.is_empty() {
+                    self.dirs_by_name.remove(&name);
+                }
+            }
+            self.file_count -= 1;
+        }
+    }
+
+    // Forget `dir` and everything beneath it.
+    fn remove_tree(&mut self, dir: &Path) {
+        let removed: Vec<PathBuf> = self
+            .files_by_dir
+            .keys()
+            .filter(|d| d.starts_with(dir))
+            .cloned()
+            .collect();
+        for d in removed {
+            self.clear_dir(&d);
+            self.files_by_dir.remove(&d);
+        }
+    }
+
+    // Apply a rescan of one directory made by `scan_dir`.
+    fn apply(&mut self, update: DirUpdate) {
+        self.clear_dir(&update.dir);
+        for dir in &update.removed_dirs {
+            self.remove_tree(dir);
+        }
+        for name in update.files {
+            self.add_file(&update.dir, name);
+        }
+        for (dir, names) in update.new_dirs.files_by_dir {
+            self.add_dir(dir.clone());
+            for name in names {
+                self.add_file(&dir, name);
+            }
+        }
+    }
+
+    fn subdirs(&self, dir: &Path) -> Vec<PathBuf> {
+        self.files_by_dir
+            .keys()
+            .filter(|d| d.parent() == Some(dir))
+            .cloned()
+            .collect()
+    }
+
+    // The one indexed file whose path ends with the relative path `suffix`.
+    fn lookup(&self, suffix: &Path) -> Option<PathBuf> {
+        if !suffix
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return None;
+        }
+        let name = suffix.file_name()?;
+        let parent = suffix.parent().unwrap_or(Path::new(""));
+        let mut candidates = self
+            .dirs_by_name
+            .get(name)?
+            .iter()
+            .filter(|dir| dir.ends_with(parent))
+            .map(|dir| dir.join(name));
+        let path = candidates.next()?;
+        candidates.next().is_none().then_some(path)
+    }
+}
+
+// The current contents of an indexed directory, read without holding the index lock.
+#[derive(Debug)]
+struct DirUpdate {
+    dir: PathBuf,
+    // Names of the files directly in `dir`
+    files: Vec<OsString>,
+    // Subdirectories that are new since the last scan, and everything beneath them
+    new_dirs: Index,
+    // Subdirectories that no longer exist, or `dir` itself
+    removed_dirs: Vec<PathBuf>,
+}
+
+pub struct FileIndex {
+    root: PathBuf,
+    index: Arc<RwLock<Index>>,
+}
+
+impl FileIndex {
+    /// Start indexing the files under `root` in the background.
+    pub fn spawn(root: PathBuf) -> Self {
+        let index = Arc::new(RwLock::new(Index::default()));
+        let thread_root = root.clone();
+        let thread_index = Arc::clone(&index);
+        thread::spawn(move || maintain(&thread_root, &thread_index));
+        Self { root, index }
+    }
+
+    /// The absolute path of the one project file whose path ends with the relative path `suffix`,
+    /// e.g. `user.rb` or `models/user.rb`. None while the index is still being built, or while a
+    /// change is being applied to it, rather than holding up the output.
+    pub fn lookup(&self, suffix: &Path) -> Option<PathBuf> {
+        let path = self.index.try_read().ok()?.lookup(suffix)?;
+        Some(self.root.join(path))
+    }
+}
+
+// Build the index, then apply filesystem changes to it for the rest of the session.
+fn maintain(root: &Path, shared: &RwLock<Index>) {
+    let (tx, rx) = mpsc::channel();
+    // Without a watcher the index is built once and never refreshed.
+    let mut watcher = notify::recommended_watcher(tx).ok();
+
+    let mut index = Index::default();
+    scan_tree(root, Path::new(""), &mut index, watcher.as_mut());
+    *shared.write().unwrap() = index;
+
+    let Some(mut watcher) = watcher else {
+        return;
+    };
+    while let Ok(event) = rx.recv() {
+        let mut changed_dirs = BTreeSet::new();
+        add_changed_dirs(root, event, &mut changed_dirs);
+        while let Ok(event) = rx.recv_timeout(SETTLE_TIME) {
+            add_changed_dirs(root, event, &mut changed_dirs);
+        }
+        let updates: Vec<DirUpdate> = changed_dirs
+            .iter()
+            .filter_map(|dir| scan_dir(root, dir, shared, &mut watcher))
+            .collect();
+        let mut index = shared.write().unwrap();
+        for update in updates {
+            index.apply(update);
+        }
+    }
+}
+
+fn add_changed_dirs(
+    root: &Path,
+    event: notify::Result<notify::Event>,
+    changed_dirs: &mut BTreeSet<PathBuf>,
+) {
+    let Ok(event) = event else {
+        return;
+    };
+    for path in event.paths {
+        let parent = path.strip_prefix(root).ok().and_then(Path::parent);
+        if let Some(dir) = parent {
+            changed_dirs.insert(dir.to_path_buf());
+        }
+    }
+}
+
+// Index `dir` (relative to `root`) and everything beneath it that is not ignored.
+fn scan_tree(
+    root: &Path,
+    dir: &Path,
+    index: &mut Index,
+    mut watcher: Option<&mut RecommendedWatcher>,
+) {
+    for entry in WalkBuilder::new(root.join(dir)).build().flatten() {
+        if index.is_full() {
+            break;
+        }
+        let Ok(relative) = entry.path().strip_prefix(root) else {
+            continue;
+        };
+        if entry.file_type().is_some_and(|t| t.is_dir()) {
+            if let Some(watcher) = watcher.as_deref_mut() {
+                let _ = watcher.watch(entry.path(), RecursiveMode::NonRecursive);
+            }
+            index.add_dir(relative.to_path_buf());
+        } else if let (Some(parent), Some(name)) = (relative.parent(), relative.file_name()) {
+            index.add_file(parent, name.to_os_string());
+        }
+    }
+}
+
+// Read the entries directly in an indexed directory, scanning new subdirectories and noting
+// deleted ones. The lock is only taken briefly to see what is indexed; as this thread is the only
+// writer, that still holds when the update is applied.
+fn scan_dir(
+    root: &Path,
+    dir: &Path,
+    shared: &RwLock<Index>,
+    watcher: &mut RecommendedWatcher,
+) -> Option<DirUpdate> {
+    let mut known_subdirs: BTreeSet<PathBuf> = {
+        let index = shared.read().unwrap();
+        if !index.files_by_dir.contains_key(dir) {
+            // Ignored, or beyond the size limit
+            return None;
+        }
+        index.subdirs(dir).into_iter().collect()
+    };
+    let mut update = DirUpdate {
+        dir: dir.to_path_buf(),
+        files: Vec::new(),
+        new_dirs: Index::default(),
+        removed_dirs: Vec::new(),
+    };
+    if !root.join(dir).is_dir() {
+        update.removed_dirs.push(dir.to_path_buf());
+        return Some(update);
+    }
+
+    let entries = WalkBuilder::new(root.join(dir)).max_depth(Some(1)).build();
+    for entry in entries.flatten().filter(|entry| entry.depth() == 1) {
+        let Ok(relative) = entry.path().strip_prefix(root) else {
+            continue;
+        };
+        if entry.file_type().is_some_and(|t| t.is_dir()) {
+            if !known_subdirs.remove(relative) {
+                scan_tree(root, relative, &mut update.new_dirs, Some(watcher));
+            }
+        } else if let Some(name) = relative.file_name() {
+            update.files.push(name.to_os_string());
+        }
+    }
+    update.removed_dirs.extend(known_subdirs);
+    Some(update)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::time::Instant;
+
+    #[test]
+    fn test_index_lookup() {
+        let mut index = Index::default();
+        index.add_file(Path::new("app/models"), "user.rb".into());
+        index.add_file(Path::new("lib/models"), "order.rb".into());
+        index.add_file(Path::new("lib"), "user.rb".into());
+        index.add_file(Path::new(""), "handler.go".into());
+
+        let lookup = |suffix: &str| index.lookup(Path::new(suffix));
+        assert_eq!(lookup("handler.go"), Some(PathBuf::from("handler.go")));
+        assert_eq!(
+            lookup("order.rb"),
+            Some(PathBuf::from("lib/models/order.rb"))
+        );
+        // Ambiguous until enough of the path is given
+        assert_eq!(lookup("user.rb"), None);
+        assert_eq!(
+            lookup("models/user.rb"),
+            Some(PathBuf::from("app/models/user.rb"))
+        );
+        assert_eq!(lookup("odels/user.rb"), None);
+        assert_eq!(lookup("../user.rb"), None);
+
+        index.remove_tree(Path::new("app"));
+        assert_eq!(
+            index.lookup(Path::new("user.rb")),
+            Some(PathBuf::from("lib/user.rb"))
+        );
+        assert_eq!(index.file_count, 3);
+    }
+
+    // Poll until `lookup` gives the expected result, as the index is updated in the background.
+    fn wait_for(index: &FileIndex, suffix: &str, expected: Option<PathBuf>) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while index.lookup(Path::new(suffix)) != expected {
+            assert!(Instant::now() < deadline, "timed out looking up {}", suffix);
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_file_index_refresh() {
+        let root = env::temp_dir().join(format!("xolmis_index_{}", std::process::id()));
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::create_dir_all(root.join("src/models")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join(".gitignore"), "/target\n").unwrap();
+        fs::write(root.join("src/models/user.rb"), "").unwrap();
+        fs::write(root.join("target/generated.rb"), "").unwrap();
+
+        let index = FileIndex::spawn(root.clone());
+        wait_for(&index, "user.rb", Some(root.join("src/models/user.rb")));
+        assert_eq!(index.lookup(Path::new("generated.rb")), None);
+
+        fs::create_dir_all(root.join("src/handlers")).unwrap();
+        fs::write(root.join("src/handlers/order.rb"), "").unwrap();
+        wait_for(&index, "order.rb", Some(root.join("src/handlers/order.rb")));
+
+        fs::remove_dir_all(root.join("src/handlers")).unwrap();
+        wait_for(&index, "order.rb", None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+// TeX engines announce each input file by printing "(" followed by its name, and print the
+// matching ")" when the file is closed:
+//
+// This is pdfTeX, Version 3.141592653-2.6-1.40.25 (TeX Live 2023) (preloaded format=pdflatex)
+// (./thesis.tex
+// LaTeX2e <2022-11-01> patch level 1
+// (/usr/share/texlive/texmf-dist/tex/latex/base/report.cls
+// Document Class: report 2022/07/02 v1.4n Standard LaTeX document class
+// (/usr/share/texlive/texmf-dist/tex/latex/base/size10.clo)) (./chapters/intro.tex
+// ! Undefined control sequence.
+// l.42 \foo
+//
+// The "l.42" refers to whichever file is open at that point: ./chapters/intro.tex.
+// `LatexFileStack` follows the parentheses across output lines while a TeX run is in progress.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+// TeX hard-wraps its terminal output at this many characters (texmf.cnf `max_print_line`).
+const MAX_PRINT_LINE: usize = 79;
+
+lazy_static! {
+    static ref LATEX_BANNER_REGEX: Regex =
+        Regex::new(r"^(?:This is (?:pdf|Xe|Lua|LuaHB|e-|e-up|up|p)?TeX, Version |Latexmk: )")
+            .unwrap();
+    static ref LATEX_END_REGEX: Regex = Regex::new(r"^Transcript written on ").unwrap();
+    // Lines quoting the source around an error. Their text is the user's TeX, so the
+    // parentheses in it say nothing about files. Each is followed by one continuation line.
+    static ref LATEX_CONTEXT_REGEX: Regex =
+        Regex::new(r"^(?:l\.\d+ |<[^>]+> |<\*> )").unwrap();
+}
+
+#[derive(Debug, Default)]
+pub struct LatexFileStack {
+    // Whether a TeX run is in progress, i.e. its banner has been seen.
+    active: bool,
+    // One entry per unclosed "(". Parentheses that do not introduce a file name are `None`.
+    stack: Vec<Option<String>>,
+    // File name cut off by TeX's line wrapping, to be completed by the next line.
+    wrapped_name: Option<String>,
+    // The next line continues a quoted source context and must not be scanned.
+    skip_next_line: bool,
+}
+
+impl LatexFileStack {
+    pub fn observe_line(&mut self, line: &str) {
+        if LATEX_BANNER_REGEX.is_match(line) {
+            *self = Self {
+                active: true,
+                ..Self::default()
+            };
+        }
+        if !self.active {
+            return;
+        }
+        if LATEX_END_REGEX.is_match(line) {
+            *self = Self::default();
+            return;
+        }
+        if std::mem::take(&mut self.skip_next_line) {
+            return;
+        }
+        if LATEX_CONTEXT_REGEX.is_match(line) {
+            self.skip_next_line = true;
+            return;
+        }
+
+        let mut chars = line.char_indices().peekable();
+        if let Some(mut name) = self.wrapped_name.take() {
+            // Only the top of the stack can have been cut off.
+            let end = line.find([' ', '(', ')']).unwrap_or(line.len());
+            name.push_str(&line[..end]);
+            self.stack.pop();
+            self.push_name(
+                name,
+                line.chars().count() >= MAX_PRINT_LINE && end == line.len(),
+            );
+            while chars.next_if(|(i, _)| *i < end).is_some() {}
+        }
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '(' => {
+                    let rest = &line[i + 1..];
+                    let end = rest.find([' ', '(', ')']).unwrap_or(rest.len());
+                    let wrapped =
+                        line.chars().count() >= MAX_PRINT_LINE && i + 1 + end == line.len();
+                    self.push_name(rest[..end].to_string(), wrapped);
+                    while chars.next_if(|(j, _)| *j <= i + end).is_some() {}
+                }
+                ')' => {
+                    self.stack.pop();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The innermost file open in the current TeX run, if any.
+    pub fn current_file(&self) -> Option<&str> {
+        if !self.active {
+            return None;
+        }
+        self.stack.iter().rev().find_map(|entry| entry.as_deref())
+    }
+
+    fn push_name(&mut self, name: String, wrapped: bool) {
+        if wrapped {
+            self.wrapped_name = Some(name.clone());
+        }
+        let is_file = wrapped || name.contains('.') || name.contains('/');
+        self.stack.push(is_file.then_some(name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observe_all(stack: &mut LatexFileStack, log: &str) {
+        for line in log.lines() {
+            stack.observe_line(line);
+        }
+    }
+
+    #[test]
+    fn test_inactive_without_banner() {
+        let mut stack = LatexFileStack::default();
+        stack.observe_line("(./chapters/intro.tex");
+        assert_eq!(stack.current_file(), None);
+    }
+
+    #[test]
+    fn test_file_stack() {
+        let mut stack = LatexFileStack::default();
+        observe_all(
+            &mut stack,
+            "This is pdfTeX, Version 3.141592653-2.6-1.40.25 (TeX Live 2023) (preloaded format=pdflatex)
+entering extended mode
+(./thesis.tex
+LaTeX2e <2022-11-01> patch level 1
+(/usr/share/texlive/texmf-dist/tex/latex/base/report.cls
+Document Class: report 2022/07/02 v1.4n Standard LaTeX document class
+(/usr/share/texlive/texmf-dist/tex/latex/base/size10.clo))",
+        );
+        assert_eq!(stack.current_file(), Some("./thesis.tex"));
+
+        stack.observe_line("(./chapters/intro.tex (see the transcript file for details)");
+        assert_eq!(stack.current_file(), Some("./chapters/intro.tex"));
+
+        // Parentheses in quoted source are not file boundaries.
+        observe_all(
+            &mut stack,
+            "! Undefined control sequence.
+l.42 \\foo)
+           ) (x)",
+        );
+        assert_eq!(stack.current_file(), Some("./chapters/intro.tex"));
+
+        stack.observe_line(")");
+        assert_eq!(stack.current_file(), Some("./thesis.tex"));
+
+        stack.observe_line("Transcript written on thesis.log.");
+        assert_eq!(stack.current_file(), None);
+    }
+
+    #[test]
+    fn test_wrapped_file_name() {
+        let mut stack = LatexFileStack::default();
+        stack.observe_line("This is XeTeX, Version 3.141592653-2.6-0.999995 (TeX Live 2023)");
+        // 79 characters, so TeX continues the file name on the next line.
+        let first =
+            "(./thesis.tex (/usr/local/texlive/2023/texmf-dist/tex/latex/koma-script/scrartc";
+        assert_eq!(first.len(), MAX_PRINT_LINE);
+        stack.observe_line(first);
+        stack.observe_line("l.cls)");
+        assert_eq!(stack.current_file(), Some("./thesis.tex"));
+        stack.observe_line(first);
+        stack.observe_line("l.cls");
+        assert_eq!(
+            stack.current_file(),
+            Some("/usr/local/texlive/2023/texmf-dist/tex/latex/koma-script/scrartcl.cls")
+        );
+        stack.observe_line(")");
+        assert_eq!(stack.current_file(), Some("./thesis.tex"));
+    }
+}
@@ -0,0 +1,313 @@
+// Optional check that a linked line exists, for output that is older than the files it refers
+// to (stale build output, logs from another branch). Line counts are cached by modification time
+// so that a file mentioned many times is only read once, as is whether a file is text, which
+// decides its link template.
+
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::rules::is_rule_name;
+use crate::template::{FileKind, Position};
+
+// Files remembered; the cache is emptied when it grows beyond this.
+const MAX_CACHED_FILES: usize = 1024;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineCheckMode {
+    // Link whatever line is printed
+    #[default]
+    Off,
+    // Do not link lines beyond the end of the file
+    Drop,
+    // Link them to the last line instead
+    Clamp,
+}
+
+/// The `[line_check]` section of the configuration.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LineCheckConfig {
+    pub mode: LineCheckMode,
+    // Overrides by rule name, e.g. `LatexLineMarker = "off"`
+    pub rules: HashMap<String, LineCheckMode>,
+}
+
+impl LineCheckConfig {
+    pub fn mode_for(&self, rule_name: &str) -> LineCheckMode {
+        self.rules.get(rule_name).copied().unwrap_or(self.mode)
+    }
+
+    // Report and drop overrides for rules that do not exist, which are most likely misspelt.
+    pub fn check_rule_names(&mut self) {
+        self.rules.retain(|name, _| {
+            let known = is_rule_name(name);
+            if !known {
+                eprintln!("xolmis: ignoring line_check rule {:?}: no such rule", name);
+            }
+            known
+        });
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LineCounter {
+    files: RefCell<HashMap<PathBuf, CachedFile>>,
+}
+
+// What is known about a file as of its modification time
+#[derive(Debug)]
+struct CachedFile {
+    modified: SystemTime,
+    line_count: Option<u32>,
+    is_text: Option<bool>,
+}
+
+impl CachedFile {
+    fn new(modified: SystemTime) -> Self {
+        Self {
+            modified,
+            line_count: None,
+            is_text: None,
+        }
+    }
+}
+
+impl LineCounter {
+    /// Apply `mode` to a position in the regular file `path`: None if it is to be dropped.
+    /// Positions without a line, and files that cannot be read, are left alone.
+    pub fn check(&self, mode: LineCheckMode, path: &Path, position: Position) -> Option<Position> {
+        let line = match (mode, position.line) {
+            (LineCheckMode::Off, _) | (_, None) => return Some(position),
+            (_, Some(line)) => line,
+        };
+        let Some(line_count) = self.line_count(path) else {
+            return Some(position);
+        };
+        // Line 1 of an empty file is still a reasonable place to open it
+        let last_line = line_count.max(1);
+        let end_line = position.end_line.unwrap_or(line);
+        if line <= last_line && end_line <= last_line {
+            return Some(position);
+        }
+        if mode == LineCheckMode::Drop {
+            return None;
+        }
+        let mut clamped = position;
+        if line > last_line {
+            clamped.line = Some(last_line);
+            clamped.column = None;
+        }
+        if position
+            .end_line
+            .is_some_and(|end_line| end_line > last_line)
+        {
+            clamped.end_line = Some(last_line);
+            clamped.end_column = None;
+        }
+        Some(clamped)
+    }
+
+    /// Whether `path` is a directory, a text file or something else; None if it does not exist.
+    pub fn file_kind(&self, path: &Path) -> Option<FileKind> {
+        let metadata = fs::metadata(path).ok()?;
+        if metadata.is_dir() {
+            return Some(FileKind::Directory);
+        }
+        // Other files than regular ones, such as FIFOs, are not read
+        let is_text = metadata.is_file()
+            && metadata.modified().ok().is_some_and(|modified| {
+                self.cached(path, modified, |file| &mut file.is_text, is_text_file)
+                    .unwrap_or(false)
+            });
+        Some(if is_text {
+            FileKind::Text
+        } else {
+            FileKind::Other
+        })
+    }
+
+    fn line_count(&self, path: &Path) -> Option<u32> {
+        let metadata = fs::metadata(path).ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+        let modified = metadata.modified().ok()?;
+        self.cached(path, modified, |file| &mut file.line_count, count_lines)
+    }
+
+    // A fact about the file `path`, from the cache unless the file was modified since it was
+    // found out.
+    fn cached<T: Copy>(
+        &self,
+        path: &Path,
+        modified: SystemTime,
+        field: fn(&mut CachedFile) -> &mut Option<T>,
+        find_out: fn(&Path) -> Option<T>,
+    ) -> Option<T> {
+        let mut files = self.files.borrow_mut();
+        let known = files
+            .get_mut(path)
+            .filter(|file| file.modified == modified)
+            .and_then(|file| *field(file));
+        if known.is_some() {
+            return known;
+        }
+        let value = find_out(path)?;
+        if files.len() >= MAX_CACHED_FILES && !files.contains_key(path) {
+            files.clear();
+        }
+        let file = files
+            .entry(path.to_path_buf())
+            .or_insert_with(|| CachedFile::new(modified));
+        if file.modified != modified {
+            *file = CachedFile::new(modified);
+        }
+        *field(file) = Some(value);
+        Some(value)
+    }
+}
+
+// The number of lines in a file, counting a final line without a newline.
+fn count_lines(path: &Path) -> Option<u32> {
+    let mut file = File::open(path).ok()?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut count = 0;
+    let mut last_byte = b'\n';
+    loop {
+        let len = file.read(&mut buf).ok()?;
+        if len == 0 {
+            break;
+        }
+        count += buf[..len].iter().filter(|&&b| b == b'\n').count();
+        last_byte = buf[len - 1];
+    }
+    if last_byte != b'\n' {
+        count += 1;
+    }
+    u32::try_from(count).ok()
+}
+
+// Whether a file looks like text, judging as git does by the absence of NUL bytes near its start.
+fn is_text_file(path: &Path) -> Option<bool> {
+    let mut buf = [0; 8000];
+    let len = File::open(path).ok()?.read(&mut buf).ok()?;
+    Some(!buf[..len].contains(&0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn at(line: u32, column: Option<u32>, end_line: Option<u32>) -> Position {
+        Position {
+            line: Some(line),
+            column,
+            end_line,
+            end_column: None,
+        }
+    }
+
+    #[test]
+    fn test_count_lines() {
+        let path = env::temp_dir().join(format!("xolmis_count_{}.txt", std::process::id()));
+        fs::write(&path, "").unwrap();
+        assert_eq!(count_lines(&path), Some(0));
+        fs::write(&path, "a\nb\n").unwrap();
+        assert_eq!(count_lines(&path), Some(2));
+        fs::write(&path, "a\nb\nc").unwrap();
+        assert_eq!(count_lines(&path), Some(3));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_check_line() {
+        let path = env::temp_dir().join(format!("xolmis_check_{}.txt", std::process::id()));
+        fs::write(&path, "1\n2\n3\n").unwrap();
+        let counter = LineCounter::default();
+
+        assert_eq!(
+            counter.check(LineCheckMode::Drop, &path, at(3, Some(2), None)),
+            Some(at(3, Some(2), None))
+        );
+        assert_eq!(
+            counter.check(LineCheckMode::Drop, &path, at(9, None, None)),
+            None
+        );
+        assert_eq!(
+            counter.check(LineCheckMode::Off, &path, at(9, None, None)),
+            Some(at(9, None, None))
+        );
+        assert_eq!(
+            counter.check(LineCheckMode::Clamp, &path, at(9, Some(4), None)),
+            Some(at(3, None, None))
+        );
+        assert_eq!(
+            counter.check(LineCheckMode::Clamp, &path, at(2, Some(4), Some(8))),
+            Some(at(2, Some(4), Some(3)))
+        );
+
+        // A rewritten file is counted again. Its modification time is set explicitly, as
+        // filesystem timestamps may be too coarse to tell two quick writes apart.
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, "1\n2\n3\n4\n5\n6\n7\n8\n9\n").unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified + std::time::Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(
+            counter.check(LineCheckMode::Drop, &path, at(9, None, None)),
+            Some(at(9, None, None))
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_kind() {
+        let dir = env::temp_dir().join(format!("xolmis_kind_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let text = dir.join("notes.md");
+        let binary = dir.join("data.bin");
+        fs::write(&text, "# Notes\n").unwrap();
+        fs::write(&binary, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+        let counter = LineCounter::default();
+
+        assert_eq!(counter.file_kind(&dir), Some(FileKind::Directory));
+        assert_eq!(counter.file_kind(&text), Some(FileKind::Text));
+        assert_eq!(counter.file_kind(&binary), Some(FileKind::Other));
+        assert_eq!(counter.file_kind(&dir.join("missing")), None);
+        // The verdict is kept alongside the line count until the file changes
+        assert_eq!(counter.line_count(&text), Some(1));
+        assert_eq!(counter.files.borrow()[&text].is_text, Some(true));
+        assert_eq!(counter.files.borrow().len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mode_for_rule() {
+        let config: LineCheckConfig =
+            toml::from_str("mode = \"clamp\"\n[rules]\nLatexLineMarker = \"off\"\n").unwrap();
+        assert_eq!(config.mode_for("FilePath"), LineCheckMode::Clamp);
+        assert_eq!(config.mode_for("LatexLineMarker"), LineCheckMode::Off);
+        assert_eq!(
+            LineCheckConfig::default().mode_for("FilePath"),
+            LineCheckMode::Off
+        );
+
+        let mut config: LineCheckConfig =
+            toml::from_str("[rules]\nLatexLineMarker = \"off\"\nLatexLineMaker = \"off\"\n")
+                .unwrap();
+        config.check_rule_names();
+        assert_eq!(config.rules.len(), 1);
+        assert!(config.rules.contains_key("LatexLineMarker"));
+    }
+}
@@ -0,0 +1,145 @@
+// Optional `id=` parameters for our OSC 8 links. Terminals treat the cells of one link as a unit
+// when hovering only if they are written in one piece; a link that is redrawn, or soft-wrapped
+// by a terminal that tracks lines separately, is highlighted fragment by fragment unless every
+// fragment carries the same id. Ids are derived from the link's URL and its position in the
+// stream, so that they are stable for a given output but two links to one place are not merged.
+//
+// Rules may instead share ids: consecutive links from such rules to the same file and line get
+// one id, so that a traceback frame and the caret line beneath it highlight together. As
+// terminals only join links whose id and URL both match, they also share the first link's URL,
+// so the column found from a caret line is not passed on.
+
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::rules::is_rule_name;
+
+/// The `[link_ids]` section of the configuration.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkIdConfig {
+    pub enabled: bool,
+    // Rules whose links share an id with the previous one to the same file and line, e.g.
+    // `["PythonTraceback", "PythonCaretLine"]`
+    pub shared_rules: Vec<String>,
+}
+
+impl LinkIdConfig {
+    // Report and drop rules that do not exist, which are most likely misspelt.
+    pub fn check_rule_names(&mut self) {
+        self.shared_rules.retain(|name| {
+            let known = is_rule_name(name);
+            if !known {
+                eprintln!("xolmis: ignoring link_ids rule {:?}: no such rule", name);
+            }
+            known
+        });
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LinkIds {
+    // Links given an id so far
+    count: u64,
+    // The file and line of the last link from a rule that shares ids, and its id and URL
+    last_shared: Option<(PathBuf, Option<u32>, String, String)>,
+}
+
+impl LinkIds {
+    /// The OSC 8 parameters and URL for the next link, to `url` at `path` and `line`. The
+    /// parameters are empty unless ids are enabled.
+    pub fn link(
+        &mut self,
+        config: &LinkIdConfig,
+        rule_name: &str,
+        url: String,
+        path: &Path,
+        line: Option<u32>,
+    ) -> (String, String) {
+        if !config.enabled {
+            return (String::new(), url);
+        }
+        self.count += 1;
+        if !config.shared_rules.iter().any(|rule| rule == rule_name) {
+            // Another link in between ends the run of shared ones
+            self.last_shared = None;
+            return (format!("id={}", link_id(&url, self.count)), url);
+        }
+        match &self.last_shared {
+            Some((last_path, last_line, id, shared_url))
+                if last_path == path && *last_line == line =>
+            {
+                (format!("id={}", id), shared_url.clone())
+            }
+            _ => {
+                let id = link_id(&url, self.count);
+                let params = format!("id={}", id);
+                self.last_shared = Some((path.to_path_buf(), line, id, url.clone()));
+                (params, url)
+            }
+        }
+    }
+}
+
+// An id made of the link's number in the stream and an FNV-1a hash of its URL, which unlike the
+// standard library's hasher is the same in every build. Only `[0-9a-z-]` is used, as `:` and `;`
+// separate OSC 8 parameters.
+fn link_id(url: &str, count: u64) -> String {
+    let hash = url.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    });
+    format!("xl{}-{:08x}", count, hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_ids() {
+        let config: LinkIdConfig = toml::from_str(
+            "enabled = true\nshared_rules = [\"PythonTraceback\", \"PythonCaretLine\"]\n",
+        )
+        .unwrap();
+        let mut ids = LinkIds::default();
+        let path = Path::new("/src/app.py");
+        let mut link =
+            |rule, url: &str, line| ids.link(&config, rule, url.to_string(), path, Some(line));
+
+        let (first, _) = link("FilePath", "x://a:3", 3);
+        let (second, _) = link("FilePath", "x://a:3", 3);
+        assert!(first.starts_with("id=xl1-"));
+        assert!(second.starts_with("id=xl2-"));
+        assert_ne!(first, second);
+
+        let frame = link("PythonTraceback", "x://a:3", 3);
+        assert_eq!(link("PythonCaretLine", "x://a:3:9", 3), frame);
+        assert_ne!(link("PythonTraceback", "x://a:4", 4).0, frame.0);
+        assert_ne!(link("PythonTraceback", "x://a:3", 3).0, frame.0);
+
+        // Only consecutive links share an id
+        let frame = link("PythonTraceback", "x://a:5", 5);
+        link("FilePath", "x://a:5", 5);
+        assert_ne!(link("PythonCaretLine", "x://a:5:2", 5).0, frame.0);
+
+        assert_eq!(link_id("", 7), "xl7-811c9dc5");
+        assert_eq!(link_id("a", 7), "xl7-e40c292c");
+
+        // Deterministic for the same output
+        let mut ids = LinkIds::default();
+        let (params, _) = ids.link(&config, "FilePath", "x://a:3".to_string(), path, Some(3));
+        assert_eq!(params, first);
+
+        let mut config: LinkIdConfig =
+            toml::from_str("shared_rules = [\"PythonTraceback\", \"PythonTracebak\"]\n").unwrap();
+        config.check_rule_names();
+        assert_eq!(config.shared_rules, ["PythonTraceback"]);
+
+        let mut ids = LinkIds::default();
+        let disabled = LinkIdConfig::default();
+        assert_eq!(
+            ids.link(&disabled, "FilePath", "x://a:3".to_string(), path, Some(3)),
+            (String::new(), "x://a:3".to_string())
+        );
+    }
+}
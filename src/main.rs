@@ -55,7 +55,20 @@ use nix::sys::termios::{self, ControlFlags, InputFlags, LocalFlags, OutputFlags,
 
 // Declare the modules responsible for transformations, ANSI parsing, and rules.
 mod ansi;
+mod config;
+mod directory_stack;
+mod escape_filter;
+mod existing_links;
+mod file_index;
+mod latex;
+mod line_check;
+mod link_ids;
+mod path_filter;
+mod python;
+mod resolve;
 mod rules;
+mod rust_src;
+mod template;
 mod transform;
 
 // Imports for streaming UTF-8 decoding.
@@ -90,6 +103,9 @@ fn main() -> io::Result<()> {
     // to resolve relative file paths found in the shell output.
     let cwd = env::current_dir()?;
 
+    // Read configuration files now, while any errors can still be reported on a cooked terminal.
+    let config = config::Config::load(&cwd);
+
     // Standard input (stdin) is the primary way xolmis interacts with the real terminal
     // for receiving user keypresses.
     let stdin = io::stdin();
@@ -100,7 +116,7 @@ fn main() -> io::Result<()> {
     // If xolmis were run with stdin piped from a file, this check would fail.
     if !stdin.is_terminal() {
         eprintln!("Error: Standard input is not a TTY.");
-        return Err(io::Error::new(io::ErrorKind::Other, "Stdin not a TTY"));
+        return Err(io::Error::other("Stdin not a TTY"));
     }
 
     // Get a safe wrapper around the raw file descriptor for stdin.
@@ -109,12 +125,8 @@ fn main() -> io::Result<()> {
 
     // Retrieve the current terminal attributes (settings) for stdin.
     // These include flags controlling input/output processing, echoing, signal handling, etc.
-    let original_termios = termios::tcgetattr(stdin_fd).map_err(|e| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to get terminal attributes: {}", e),
-        )
-    })?;
+    let original_termios = termios::tcgetattr(stdin_fd)
+        .map_err(|e| io::Error::other(format!("Failed to get terminal attributes: {}", e)))?;
 
     // Instantiate the TermRestore struct. It clones the original settings.
     // `_term_restore` going out of scope at the end of `main` triggers its `drop` method
@@ -164,12 +176,8 @@ fn main() -> io::Result<()> {
 
     // Apply the raw mode settings to the terminal.
     // println!("Applying raw mode terminal settings..."); // Can be noisy
-    termios::tcsetattr(stdin_fd, termios::SetArg::TCSANOW, &raw_termios).map_err(|e| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to set raw terminal attributes: {}", e),
-        )
-    })?;
+    termios::tcsetattr(stdin_fd, termios::SetArg::TCSANOW, &raw_termios)
+        .map_err(|e| io::Error::other(format!("Failed to set raw terminal attributes: {}", e)))?;
 
     // Get the initial size (rows, columns) of the real terminal.
     // This is crucial for setting the corresponding size of the PTY.
@@ -188,7 +196,7 @@ fn main() -> io::Result<()> {
     // - `pts`: The slave end. This is passed to the child process (the shell) to use
     //          as its controlling terminal (its stdin, stdout, stderr).
     let (pty, pts): (Pty, Pts) = pty_process::blocking::open()
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Failed to open PTY: {}", e)))?;
+        .map_err(|e| io::Error::other(format!("Failed to open PTY: {}", e)))?;
 
     // Set initial PTY size.
     // Programs running inside the PTY (like fzf, editors) query the PTY's size
@@ -199,9 +207,8 @@ fn main() -> io::Result<()> {
         let pty_size = Size::new(rows, cols);
         // The `resize` method borrows `pty` mutably here, which is allowed even if
         // the `pty` binding isn't `mut`.
-        pty.resize(pty_size).map_err(|e| {
-            io::Error::new(io::ErrorKind::Other, format!("Failed to resize PTY: {}", e))
-        })?;
+        pty.resize(pty_size)
+            .map_err(|e| io::Error::other(format!("Failed to resize PTY: {}", e)))?;
     } else {
         eprintln!("Warning: Could not get terminal size. PTY might have incorrect dimensions.");
     }
@@ -210,12 +217,9 @@ fn main() -> io::Result<()> {
     // Spawn the user's shell as a child process.
     // Critically, the shell is attached to the PTY slave end (`pts`).
     // `cmd.spawn()` takes ownership of `pts`.
-    let mut child: Child = cmd.spawn(pts).map_err(|e| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to spawn process: {}", e),
-        )
-    })?;
+    let mut child: Child = cmd
+        .spawn(pts)
+        .map_err(|e| io::Error::other(format!("Failed to spawn process: {}", e)))?;
 
     // println!("PTY spawned successfully."); // Can be noisy
 
@@ -277,8 +281,10 @@ fn main() -> io::Result<()> {
     });
     // --- End SIGWINCH Handler Thread ---
 
-    // Clone the current working directory for the output thread.
-    let thread_cwd = cwd.clone();
+    // The output thread's transformer resolves relative paths against the current working
+    // directory, and carries context (such as build-tool directories) from chunk to chunk.
+    let mut transformer = transform::Transformer::with_config(cwd.clone(), config);
+    transformer.index_project_files();
 
     // --- Output Thread ---
     // Reads output from the shell (via PTY master), decodes UTF-8, transforms it,
@@ -312,7 +318,7 @@ fn main() -> io::Result<()> {
                     match std::str::from_utf8(decoded_bytes) {
                         Ok(decoded_str) => {
                             // Pass the valid string chunk to the transformation logic.
-                            let transformed_str = transform::transform(decoded_str, &thread_cwd);
+                            let transformed_str = transformer.transform(decoded_str);
 
                             // Write the (potentially transformed) result to the real terminal stdout.
                             let mut stdout = io::stdout().lock();
@@ -343,6 +349,10 @@ fn main() -> io::Result<()> {
                 }
             }
         }
+        // Output held back in the middle of an escape sequence that never ended
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(transformer.finish().as_bytes());
+        let _ = stdout.flush();
     });
     // --- End Output Thread ---
 
@@ -392,10 +402,7 @@ fn main() -> io::Result<()> {
             eprintln!("Failed to wait for child process: {}", e);
             // If waiting fails, return an error. This would trigger terminal restore
             // if main returned Result, but `process::exit` bypasses it.
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Failed to wait for child",
-            ));
+            return Err(io::Error::other("Failed to wait for child"));
         }
     };
 
@@ -0,0 +1,228 @@
+// Which resolved paths may be linked. Build output can mention thousands of paths under
+// `target/`, `node_modules/` or `.venv/`; excluding them leaves them as plain text and saves the
+// `stat` that checking each one would cost, as the filter is applied before the existence check.
+//
+// Globs are matched against the absolute path and each of its parent directories, so `target`
+// excludes everything beneath a directory of that name. Globs not starting with `/` or `~` match
+// at any depth. `*` does not match `/`; `**` does.
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use crate::resolve::expand_path;
+
+// Directories whose .gitignore is remembered; the cache is emptied when it grows beyond this.
+const MAX_CACHED_DIRS: usize = 1024;
+
+/// The `[filter]` section of the configuration.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    // If given, only paths matching one of these are linked
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    // Also exclude paths ignored by the .gitignore files of the git work tree
+    pub gitignore: bool,
+}
+
+#[derive(Debug)]
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    // The work tree root, if .gitignore files are honoured
+    git_root: Option<PathBuf>,
+    // The .gitignore file of each directory consulted so far, if it has one
+    gitignores: RefCell<HashMap<PathBuf, Option<Gitignore>>>,
+}
+
+impl PathFilter {
+    pub fn new(config: &FilterConfig, git_root: Option<&Path>) -> Self {
+        Self {
+            include: (!config.include.is_empty()).then(|| build_glob_set(&config.include)),
+            exclude: build_glob_set(&config.exclude),
+            git_root: git_root.filter(|_| config.gitignore).map(Path::to_path_buf),
+            gitignores: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `path` (absolute) may be linked. Does not touch the filesystem, apart from
+    /// reading .gitignore files, which are remembered.
+    pub fn allows(&self, path: &Path) -> bool {
+        let path = normalize(path);
+        let matches = |set: &GlobSet| path.ancestors().any(|p| set.is_match(p));
+        if self.include.as_ref().is_some_and(|set| !matches(set)) || matches(&self.exclude) {
+            return false;
+        }
+        !self.is_git_ignored(&path)
+    }
+
+    // Whether the nearest .gitignore with a pattern matching `path` ignores it. Whether `path` is
+    // a directory is not known without a `stat`, so patterns such as `build/` only apply to the
+    // directories above it.
+    fn is_git_ignored(&self, path: &Path) -> bool {
+        let Some(root) = self.git_root.as_deref() else {
+            return false;
+        };
+        if !path.starts_with(root) {
+            return false;
+        }
+        let mut gitignores = self.gitignores.borrow_mut();
+        for dir in path.ancestors().skip(1) {
+            if gitignores.len() >= MAX_CACHED_DIRS && !gitignores.contains_key(dir) {
+                gitignores.clear();
+            }
+            let gitignore = gitignores
+                .entry(dir.to_path_buf())
+                .or_insert_with(|| read_gitignore(dir, dir == root));
+            if let Some(gitignore) = gitignore {
+                match gitignore.matched_path_or_any_parents(path, false) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+            if dir == root {
+                break;
+            }
+        }
+        false
+    }
+}
+
+// The patterns of `dir`'s .gitignore, and for the work tree root also .git/info/exclude.
+fn read_gitignore(dir: &Path, is_root: bool) -> Option<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir);
+    builder.add(dir.join(".gitignore"));
+    if is_root {
+        builder.add(dir.join(".git/info/exclude"));
+    }
+    builder
+        .build()
+        .ok()
+        .filter(|gitignore| !gitignore.is_empty())
+}
+
+// Compile globs from the configuration, reporting and skipping invalid ones.
+fn build_glob_set(patterns: &[String]) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = glob_pattern(pattern).and_then(|glob| {
+            GlobBuilder::new(&glob)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| e.to_string())
+        });
+        match glob {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(e) => eprintln!("xolmis: ignoring filter glob {:?}: {}", pattern, e),
+        }
+    }
+    builder.build().unwrap_or_else(|e| {
+        eprintln!("xolmis: ignoring filter globs: {}", e);
+        GlobSet::empty()
+    })
+}
+
+// The glob to match absolute paths with: `~` expanded, and relative globs matching at any depth.
+fn glob_pattern(pattern: &str) -> Result<String, String> {
+    if pattern.starts_with('~') {
+        let expanded = expand_path(pattern).ok_or("cannot expand it")?;
+        return Ok(expanded.into_owned());
+    }
+    if pattern.starts_with('/') {
+        return Ok(pattern.to_string());
+    }
+    Ok(format!("**/{}", pattern.trim_start_matches("./")))
+}
+
+// Remove `.` and `..` components without consulting the filesystem, so that `src/../target/x`
+// is excluded along with `target/x`.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn filter(toml: &str) -> PathFilter {
+        let config: FilterConfig = toml::from_str(toml).unwrap();
+        PathFilter::new(&config, None)
+    }
+
+    #[test]
+    fn test_exclude_globs() {
+        let filter = filter("exclude = [\"target\", \"node_modules\", \"*.min.js\", \"/opt/**\"]");
+        let allows = |path: &str| filter.allows(Path::new(path));
+        assert!(allows("/src/app/main.rs"));
+        assert!(!allows("/src/app/target/debug/build.rs"));
+        assert!(!allows("/src/app/web/node_modules/react/index.js"));
+        assert!(!allows("/src/app/dist/app.min.js"));
+        assert!(!allows("/opt/lib/x.py"));
+        assert!(!allows("/src/app/src/../target/x.rs"));
+        // Whole components only
+        assert!(allows("/src/app/targets/x.rs"));
+        assert!(allows("/src/app/dist/app.js"));
+    }
+
+    #[test]
+    fn test_include_globs() {
+        let filter = filter("include = [\"src/**\", \"*.toml\"]\nexclude = [\"generated\"]");
+        let allows = |path: &str| filter.allows(Path::new(path));
+        assert!(allows("/repo/src/main.rs"));
+        assert!(allows("/repo/Cargo.toml"));
+        assert!(!allows("/repo/build.rs"));
+        assert!(!allows("/repo/src/generated/x.rs"));
+    }
+
+    #[test]
+    fn test_gitignore() {
+        let root = env::temp_dir().join(format!("xolmis_filter_{}", std::process::id()));
+        fs::create_dir_all(root.join("web")).unwrap();
+        fs::write(root.join(".gitignore"), "/target\n*.log\n!keep.log\n").unwrap();
+        fs::write(root.join("web/.gitignore"), "dist/\n").unwrap();
+        let config = FilterConfig {
+            gitignore: true,
+            ..FilterConfig::default()
+        };
+        let filter = PathFilter::new(&config, Some(&root));
+        let allows = |path: &str| filter.allows(&root.join(path));
+        assert!(allows("src/main.rs"));
+        assert!(!allows("target/debug/x.rs"));
+        assert!(!allows("web/server.log"));
+        assert!(allows("web/keep.log"));
+        assert!(!allows("web/dist/app.js"));
+        assert!(allows("dist/app.js"));
+        assert!(filter.allows(Path::new("/elsewhere/target/x.rs")));
+
+        // The .gitignore files remembered are bounded, however many directories are seen
+        for i in 0..MAX_CACHED_DIRS + 10 {
+            assert!(!allows(&format!("gen{}/out.log", i)));
+        }
+        assert!(filter.gitignores.borrow().len() <= MAX_CACHED_DIRS);
+
+        // Only with the option
+        let filter = PathFilter::new(&FilterConfig::default(), Some(&root));
+        assert!(filter.allows(&root.join("target/debug/x.rs")));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+// Since 3.11, Python tracebacks mark the failing expression beneath the quoted source line:
+//
+//   File "/proj/app/models.py", line 42, in total
+//     return self.price * self.quantity + shipping[region]
+//                                         ~~~~~~~~^^^^^^^^
+//
+// Python prints the source line with its indentation replaced by four spaces, so the column of
+// the marked expression in the file is found by locating the quoted source in the file's line.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::rules::{PYTHON_CARET_LINE_REGEX, PYTHON_TRACE_REGEX_OPT_LINE};
+
+lazy_static! {
+    static ref PYTHON_FRAME_REGEX: Regex = Regex::new(PYTHON_TRACE_REGEX_OPT_LINE).unwrap();
+    static ref CARET_LINE_REGEX: Regex = Regex::new(PYTHON_CARET_LINE_REGEX).unwrap();
+}
+
+// A traceback frame, and the lines printed beneath it so far.
+#[derive(Debug)]
+struct Frame {
+    path: String,
+    line: u32,
+    source_line: Option<String>,
+    lines_after: usize,
+}
+
+// Remembers the most recent `File "...", line N` frame so that a caret line arriving in a later
+// chunk can still be linked to it.
+#[derive(Debug, Default)]
+pub struct PythonFrameTracker {
+    frame: Option<Frame>,
+}
+
+impl PythonFrameTracker {
+    pub fn observe_line(&mut self, line: &str) {
+        if let Some(caps) = PYTHON_FRAME_REGEX.captures(line) {
+            self.frame = caps.name("line").and_then(|line_num| {
+                Some(Frame {
+                    path: caps["path"].to_string(),
+                    line: line_num.as_str().parse().ok()?,
+                    source_line: None,
+                    lines_after: 0,
+                })
+            });
+        } else if let Some(frame) = &mut self.frame {
+            frame.lines_after += 1;
+            match frame.lines_after {
+                1 => frame.source_line = Some(line.to_string()),
+                _ => self.frame = None,
+            }
+        }
+    }
+
+    /// The frame whose quoted source line was the last line observed, i.e. the frame that a caret
+    /// line at this point belongs to: (path, line number, source line).
+    pub fn frame_awaiting_caret_line(&self) -> Option<(&str, u32, &str)> {
+        let frame = self.frame.as_ref()?;
+        Some((&frame.path, frame.line, frame.source_line.as_deref()?))
+    }
+}
+
+/// The 1-based column in `path` at line `line_num` of the expression marked by `caret_line`
+/// beneath the quoted `source_line`.
+pub fn caret_column(
+    path: &Path,
+    line_num: u32,
+    source_line: &str,
+    caret_line: &str,
+) -> Option<u32> {
+    if !CARET_LINE_REGEX.is_match(caret_line) {
+        return None;
+    }
+    let source_indent = source_line
+        .chars()
+        .take_while(|c| c.is_whitespace())
+        .count();
+    let caret_indent = caret_line.chars().take_while(|c| c.is_whitespace()).count();
+    let offset_in_source = caret_indent.checked_sub(source_indent)?;
+
+    let file_line = BufReader::new(File::open(path).ok()?)
+        .lines()
+        .nth(line_num.checked_sub(1)? as usize)?
+        .ok()?;
+    let source = source_line.trim();
+    let source_start = file_line.find(source)?;
+    let column = file_line[..source_start].chars().count() + offset_in_source + 1;
+    u32::try_from(column).ok()
+}
+
+// The parts of a notebook needed to find a cell's source. Sources are kept as they appear in the
+// file, so that where they are in it can be worked out.
+#[derive(Deserialize)]
+struct Notebook<'a> {
+    #[serde(borrow)]
+    cells: Vec<Cell<'a>>,
+}
+
+#[derive(Deserialize)]
+struct Cell<'a> {
+    execution_count: Option<u32>,
+    #[serde(borrow)]
+    source: Option<&'a RawValue>,
+}
+
+/// The line of a notebook's JSON holding line `line_num` of the code cell that was executed
+/// with `execution_count`, as referred to by IPython's `Cell In[N], line M` frames. A source
+/// saved as a list of strings, as Jupyter does, gives the line of the string holding that line;
+/// a source saved as one string gives the line that string starts on.
+pub fn notebook_cell_line(notebook: &Path, execution_count: u32, line_num: u32) -> Option<u32> {
+    let contents = std::fs::read_to_string(notebook).ok()?;
+    let notebook: Notebook = serde_json::from_str(&contents).ok()?;
+    let source = notebook
+        .cells
+        .iter()
+        .find(|cell| cell.execution_count == Some(execution_count))?
+        .source?;
+    let parts =
+        serde_json::from_str::<Vec<&RawValue>>(source.get()).unwrap_or_else(|_| vec![source]);
+
+    // The last part starting at or before the line
+    let line_index = line_num.checked_sub(1)? as usize;
+    let mut newlines = 0;
+    let mut part_holding_line = None;
+    for part in parts {
+        if newlines > line_index {
+            break;
+        }
+        part_holding_line = Some(part);
+        newlines += serde_json::from_str::<String>(part.get())
+            .ok()?
+            .matches('\n')
+            .count();
+    }
+    if newlines < line_index {
+        return None;
+    }
+
+    // The raw values borrow from `contents`
+    let offset = part_holding_line?.get().as_ptr() as usize - contents.as_ptr() as usize;
+    u32::try_from(contents[..offset].matches('\n').count() + 1).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_caret_column() {
+        let path = env::temp_dir().join(format!("xolmis_caret_{}.py", std::process::id()));
+        std::fs::write(
+            &path,
+            "class Order:\n    def total(self):\n        return self.price + shipping[region]\n",
+        )
+        .unwrap();
+        let source_line = "    return self.price + shipping[region]";
+        let caret_line = "                        ~~~~~~~~^^^^^^^^";
+        assert_eq!(caret_column(&path, 3, source_line, caret_line), Some(29));
+        assert_eq!(caret_column(&path, 3, source_line, "SyntaxError"), None);
+        assert_eq!(caret_column(&path, 2, source_line, caret_line), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_frame_tracker() {
+        let mut tracker = PythonFrameTracker::default();
+        tracker.observe_line("Traceback (most recent call last):");
+        assert_eq!(tracker.frame_awaiting_caret_line(), None);
+        tracker.observe_line("  File \"/proj/app.py\", line 3, in <module>");
+        assert_eq!(tracker.frame_awaiting_caret_line(), None);
+        tracker.observe_line("    main()");
+        assert_eq!(
+            tracker.frame_awaiting_caret_line(),
+            Some(("/proj/app.py", 3, "    main()"))
+        );
+        tracker.observe_line("    ^^^^^^");
+        assert_eq!(tracker.frame_awaiting_caret_line(), None);
+    }
+
+    #[test]
+    fn test_notebook_cell_line() {
+        let path = env::temp_dir().join(format!("xolmis_cell_{}.ipynb", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{
+ "cells": [
+  {
+   "cell_type": "code",
+   "execution_count": 2,
+   "id": "a1",
+   "metadata": {},
+   "outputs": [],
+   "source": [
+    "import math"
+   ]
+  },
+  {
+   "cell_type": "code",
+   "execution_count": 3,
+   "id": "b2",
+   "metadata": {},
+   "outputs": [],
+   "source": [
+    "x = 0\n",
+    "math.log(x)"
+   ]
+  }
+ ]
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(notebook_cell_line(&path, 2, 1), Some(10));
+        assert_eq!(notebook_cell_line(&path, 3, 1), Some(20));
+        assert_eq!(notebook_cell_line(&path, 3, 2), Some(21));
+        assert_eq!(notebook_cell_line(&path, 3, 3), None);
+        assert_eq!(notebook_cell_line(&path, 4, 1), None);
+
+        // Minified, with `execution_count` after the source
+        std::fs::write(
+            &path,
+            r##"{"cells":[{"cell_type":"markdown","source":"# Title"},{"cell_type":"code","source":["x = 0\n","math.log(x)"],"execution_count":3}],"metadata":{}}"##,
+        )
+        .unwrap();
+        assert_eq!(notebook_cell_line(&path, 3, 2), Some(1));
+
+        // A source saved as one string
+        std::fs::write(
+            &path,
+            "{\"cells\": [\n {\"source\": \"x = 0\\nmath.log(x)\",\n  \"execution_count\": 3}\n]}\n",
+        )
+        .unwrap();
+        assert_eq!(notebook_cell_line(&path, 3, 2), Some(2));
+        assert_eq!(notebook_cell_line(&path, 3, 3), None);
+
+        std::fs::write(&path, "not json").unwrap();
+        assert_eq!(notebook_cell_line(&path, 3, 1), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
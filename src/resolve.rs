@@ -0,0 +1,271 @@
+// Turning paths as printed by programs into paths on this machine.
+
+use lazy_static::lazy_static;
+use nix::unistd::User;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::path::{Path, PathBuf};
+
+lazy_static! {
+    static ref ENV_VAR_REGEX: Regex = Regex::new(
+        r"\$(?:\{(?P<braced>[A-Za-z_][A-Za-z0-9_]*)\}|(?P<bare>[A-Za-z_][A-Za-z0-9_]*))"
+    )
+    .unwrap();
+}
+
+/// Expand a leading `~` or `~user`, and `$VAR` or `${VAR}` references, as a shell would. The
+/// variables are those of the environment that xolmis, and therefore the shell, started with.
+/// Returns None if the path refers to an unknown user or an unset variable.
+pub fn expand_path(path_str: &str) -> Option<Cow<'_, str>> {
+    expand_path_with(path_str, home_dir, |name| env::var(name).ok())
+}
+
+/// Home directories by user name, looked up once each, as a lookup may go to a directory service
+/// such as LDAP.
+#[derive(Debug, Default)]
+pub struct HomeDirs {
+    dirs: RefCell<HashMap<String, Option<PathBuf>>>,
+}
+
+impl HomeDirs {
+    /// `expand_path`, with home directories from the cache.
+    pub fn expand_path<'a>(&self, path_str: &'a str) -> Option<Cow<'a, str>> {
+        expand_path_with(
+            path_str,
+            |user| self.home_dir(user),
+            |name| env::var(name).ok(),
+        )
+    }
+
+    fn home_dir(&self, user: &str) -> Option<PathBuf> {
+        self.dirs
+            .borrow_mut()
+            .entry(user.to_string())
+            .or_insert_with(|| home_dir(user))
+            .clone()
+    }
+}
+
+fn expand_path_with<'a>(
+    path_str: &'a str,
+    home_dir: impl Fn(&str) -> Option<PathBuf>,
+    var: impl Fn(&str) -> Option<String>,
+) -> Option<Cow<'a, str>> {
+    let mut expanded = Cow::Borrowed(path_str);
+
+    if let Some(rest) = path_str.strip_prefix('~') {
+        let (user, rest) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let home = home_dir(user)?;
+        expanded = Cow::Owned(format!("{}{}", home.to_string_lossy(), rest));
+    }
+
+    if expanded.contains('$') {
+        let mut unset = false;
+        let replaced = ENV_VAR_REGEX.replace_all(&expanded, |caps: &Captures| {
+            let name = caps.name("braced").or(caps.name("bare")).unwrap().as_str();
+            var(name).unwrap_or_else(|| {
+                unset = true;
+                String::new()
+            })
+        });
+        if unset {
+            return None;
+        }
+        expanded = Cow::Owned(replaced.into_owned());
+    }
+
+    Some(expanded)
+}
+
+/// The path named by a matched path string: the text between its quotes if it is quoted, and
+/// otherwise with backslash-escaped spaces unescaped, as a shell would read it.
+pub fn unquote_path(path_str: &str) -> Cow<'_, str> {
+    for quote in ['\'', '"', '`'] {
+        let inner = path_str
+            .strip_prefix(quote)
+            .and_then(|rest| rest.strip_suffix(quote));
+        if let Some(inner) = inner {
+            return Cow::Borrowed(inner);
+        }
+    }
+    if path_str.contains("\\ ") {
+        Cow::Owned(path_str.replace("\\ ", " "))
+    } else {
+        Cow::Borrowed(path_str)
+    }
+}
+
+/// Expand `~` and environment variables in a path from the configuration and make it absolute.
+/// Reports and returns None if it cannot be expanded.
+pub fn expand_config_path(path: &Path, base_dir: &Path) -> Option<PathBuf> {
+    match expand_path(&path.to_string_lossy()) {
+        Some(expanded) => Some(base_dir.join(expanded.as_ref())),
+        None => {
+            eprintln!(
+                "xolmis: ignoring {} in configuration: cannot expand it",
+                path.display()
+            );
+            None
+        }
+    }
+}
+
+/// The root of the git work tree containing `dir`: the nearest directory with a `.git` entry,
+/// which is a file rather than a directory in linked worktrees and submodules.
+pub fn git_work_tree_root(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .find(|ancestor| ancestor.join(".git").exists())
+        .map(Path::to_path_buf)
+}
+
+/// Path prefix mappings for output from containers and build sandboxes, whose paths do not exist
+/// on the host, e.g. `"/app" = "~/src/service"`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct PathMap(BTreeMap<PathBuf, PathBuf>);
+
+impl PathMap {
+    // Expand and make absolute the host side of each mapping, dropping those that cannot be
+    // expanded.
+    pub fn with_base_dir(self, base_dir: &Path) -> Self {
+        let mappings = self
+            .0
+            .into_iter()
+            .filter_map(|(from, to)| Some((from, expand_config_path(&to, base_dir)?)))
+            .collect();
+        Self(mappings)
+    }
+
+    /// Rewrite `path` using the mapping with the longest prefix that matches it, comparing whole
+    /// components so that `/app` does not match `/application`.
+    pub fn apply<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        let mapping = self
+            .0
+            .iter()
+            .filter(|(from, _)| path.starts_with(from))
+            .max_by_key(|(from, _)| from.components().count());
+        match mapping {
+            Some((from, to)) => Cow::Owned(to.join(path.strip_prefix(from).unwrap())),
+            None => Cow::Borrowed(path),
+        }
+    }
+}
+
+// The home directory of `user`, or of the current user if `user` is empty.
+fn home_dir(user: &str) -> Option<PathBuf> {
+    if user.is_empty() {
+        if let Some(home) = env::var_os("HOME") {
+            return Some(PathBuf::from(home));
+        }
+        return User::from_uid(nix::unistd::getuid()).ok()?.map(|u| u.dir);
+    }
+    User::from_name(user).ok()?.map(|u| u.dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(path_str: &str) -> Option<String> {
+        let home_dir = |user: &str| match user {
+            "" => Some(PathBuf::from("/home/me")),
+            "alice" => Some(PathBuf::from("/home/alice")),
+            _ => None,
+        };
+        let var = |name: &str| match name {
+            "PROJ" => Some("/src/proj".to_string()),
+            "SUB" => Some("lib".to_string()),
+            _ => None,
+        };
+        expand_path_with(path_str, home_dir, var).map(|p| p.into_owned())
+    }
+
+    #[test]
+    fn test_expand_tilde() {
+        assert_eq!(
+            expand("~/src/app/main.py").as_deref(),
+            Some("/home/me/src/app/main.py")
+        );
+        assert_eq!(expand("~").as_deref(), Some("/home/me"));
+        assert_eq!(expand("~alice/x.rs").as_deref(), Some("/home/alice/x.rs"));
+        assert_eq!(expand("~nobody/x.rs"), None);
+        // Only a leading tilde is special
+        assert_eq!(expand("src/~/x.rs").as_deref(), Some("src/~/x.rs"));
+    }
+
+    #[test]
+    fn test_home_dirs() {
+        let home_dirs = HomeDirs::default();
+        let root_home = User::from_name("root").unwrap().unwrap().dir;
+        for _ in 0..2 {
+            assert_eq!(
+                home_dirs.expand_path("~root/x.py").as_deref(),
+                Some(root_home.join("x.py").to_str().unwrap())
+            );
+        }
+        assert_eq!(home_dirs.expand_path("~no_such_user_xolmis/x.py"), None);
+        assert_eq!(home_dirs.dirs.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_expand_env_vars() {
+        assert_eq!(expand("$PROJ/x.rs").as_deref(), Some("/src/proj/x.rs"));
+        assert_eq!(
+            expand("${PROJ}/${SUB}/x.rs").as_deref(),
+            Some("/src/proj/lib/x.rs")
+        );
+        assert_eq!(expand("$PROJ_DIR/x.rs"), None);
+        assert_eq!(expand("src/x.rs").as_deref(), Some("src/x.rs"));
+    }
+
+    #[test]
+    fn test_unquote_path() {
+        assert_eq!(unquote_path("'dir with space/x.rs'"), "dir with space/x.rs");
+        assert_eq!(unquote_path("\"My Project/a.py\""), "My Project/a.py");
+        assert_eq!(unquote_path("`docs/read me.txt`"), "docs/read me.txt");
+        assert_eq!(unquote_path("My\\ Project/main.py"), "My Project/main.py");
+        assert_eq!(unquote_path("src/main.rs"), "src/main.rs");
+        assert_eq!(unquote_path("'"), "'");
+    }
+
+    #[test]
+    fn test_path_map() {
+        let path_map: PathMap =
+            toml::from_str("\"/app\" = \"/home/me/service\"\n\"/app/vendor\" = \"/opt/vendor\"\n")
+                .unwrap();
+        let apply = |path: &str| path_map.apply(Path::new(path)).into_owned();
+        assert_eq!(
+            apply("/app/src/handler.py"),
+            PathBuf::from("/home/me/service/src/handler.py")
+        );
+        assert_eq!(
+            apply("/app/vendor/lib.py"),
+            PathBuf::from("/opt/vendor/lib.py")
+        );
+        assert_eq!(
+            apply("/application/x.py"),
+            PathBuf::from("/application/x.py")
+        );
+        assert_eq!(apply("src/app/x.py"), PathBuf::from("src/app/x.py"));
+    }
+
+    #[test]
+    fn test_git_work_tree_root() {
+        let dir = env::temp_dir().join(format!("xolmis_git_root_{}", std::process::id()));
+        let sub = dir.join("repo/src/deep");
+        std::fs::create_dir_all(&sub).unwrap();
+        std::fs::write(dir.join("repo/.git"), "gitdir: ../.git/worktrees/repo\n").unwrap();
+        assert_eq!(git_work_tree_root(&sub), Some(dir.join("repo")));
+        assert_eq!(git_work_tree_root(&dir), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_home_dir_of_current_user() {
+        assert!(home_dir("").is_some());
+    }
+}
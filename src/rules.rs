@@ -19,8 +19,16 @@ use regex::Regex;
 pub struct RuleData {
     pub name: &'static str,
     pub regex_str: &'static str,
-    pub path_group_name: &'static str,
+    // None if the path is not in the match but comes from the surrounding output (see
+    // `latex::LatexFileStack`).
+    pub path_group_name: Option<&'static str>,
+    // If given, only matches with a line number are linked. None if the line number, if any,
+    // comes from the surrounding output.
     pub line_group_name: Option<&'static str>,
+    // Optional column, and the end of a range such as "src/x.rs:10-20" or "#L10C5-L20C8".
+    pub column_group_name: Option<&'static str>,
+    pub end_line_group_name: Option<&'static str>,
+    pub end_column_group_name: Option<&'static str>,
 }
 
 // Structure holding the compiled regex and other rule info
@@ -28,40 +36,177 @@ pub struct RuleData {
 pub struct CompiledRule {
     pub name: &'static str,
     pub regex: Regex, // Compiled regex
-    pub path_group_index: usize,
+    pub path_group_index: Option<usize>,
     pub line_group_index: Option<usize>,
+    pub column_group_index: Option<usize>,
+    pub end_line_group_index: Option<usize>,
+    pub end_column_group_index: Option<usize>,
 }
 
-// Regex to capture file paths, optionally followed by :line_number.
-// Matches paths starting with /, ./, ../, ~, or C:\, or containing at least one / or \.
+// Regex to capture file paths, optionally followed by :line_number, :line:column, or a range
+// :line[:column]-end_line[:end_column].
+// Matches paths starting with /, ./, ../, ~, ~user, $VAR, ${VAR}, C:\ or an elided …/ or .../,
+// or containing at least one / or \. Components may contain Unicode letters and digits, brackets
+// (`app/(group)/[id].tsx`), and spaces escaped with a backslash as printed by shells
+// (`My\ Project/main.py`). Trailing punctuation and unbalanced closing brackets are trimmed from
+// the path afterwards (see `transform::trim_trailing_punctuation`), so that brackets around a
+// path are told from brackets in it.
 // It avoids matching URLs like http://... by requiring path characters.
-const FILE_PATH_REGEX_OPT_LINE: &str = r"(?P<path>(?:[~.]/[a-zA-Z0-9._\\/~-]+)|(?:/[a-zA-Z0-9._~-][a-zA-Z0-9._\\/~-]*)|(?:[a-zA-Z]:\\[a-zA-Z0-9._\\/~-]+)|(?:\b[a-zA-Z0-9._~-]+/[a-zA-Z0-9._\\/~-]+)|(?:\b[a-zA-Z0-9_-]+\.[a-zA-Z0-9]+))(?::(?P<line>\d+))?\b";
+macro_rules! file_path_pattern {
+    () => {
+        r"(?P<path>(?:(?:…|\.\.\.)/(?:\\ |[\w.\\/~()\[\]{}-])+)|(?:\$\{?[a-zA-Z_][a-zA-Z0-9_]*\}?/(?:\\ |[\w.\\/~()\[\]{}-])+)|(?:(?:~[\w.-]*|\.)/(?:\\ |[\w.\\/~()\[\]{}-])+)|(?:/[\w.~-](?:\\ |[\w.\\/~()\[\]{}-])*)|(?:[a-zA-Z]:\\(?:\\ |[\w.\\/~()\[\]{}-])+)|(?:\b[\w.~-](?:\\ |[\w.~-])*/(?:\\ |[\w.\\/~()\[\]{}-])+)|(?:\b[\w-]+\.[a-zA-Z0-9]+\b))"
+    };
+}
+const FILE_PATH_REGEX_OPT_LINE: &str = concat!(
+    file_path_pattern!(),
+    r"(?::(?P<line>\d+)\b(?::(?P<column>\d+)\b)?(?:-(?P<end_line>\d+)\b(?::(?P<end_column>\d+)\b)?)?)?"
+);
+
+// A path with a GitHub-style line anchor, e.g. "src/x.rs#L10", "src/x.rs#L10-L20" or
+// "src/x.rs#L10C5-L20C8".
+const FILE_PATH_ANCHOR_REGEX: &str = concat!(
+    file_path_pattern!(),
+    r"#L(?P<line>\d+)(?:C(?P<column>\d+))?(?:-L(?P<end_line>\d+)(?:C(?P<end_column>\d+))?)?\b"
+);
+
+// A path followed by a line or range in words, e.g. "src/x.rs, lines 10 to 20".
+const FILE_PATH_LINES_REGEX: &str = concat!(
+    file_path_pattern!(),
+    r",? lines? (?P<line>\d+)(?:(?: to |-|–)(?P<end_line>\d+))?\b"
+);
+
+// A path in single, double or back quotes, which may contain spaces, optionally followed by
+// :line_number, e.g. "'src/dir with space/x.rs':10". The quoted text must contain a / or . so
+// that quoted words are not looked up; the path group includes the quotes.
+const QUOTED_FILE_PATH_REGEX: &str = r#"(?P<path>'[^'\s][^'\n]*[/.][^'\n]*'|"[^"\s][^"\n]*[/.][^"\n]*"|`[^`\s][^`\n]*[/.][^`\n]*`)(?::(?P<line>\d+)\b)?"#;
 
 // Python traceback pattern (optional line)
-const PYTHON_TRACE_REGEX_OPT_LINE: &str = r#"^\s*File "(?P<path>.*?)"(?:, line (?P<line>\d+))?"#;
+pub const PYTHON_TRACE_REGEX_OPT_LINE: &str =
+    r#"(?m)^[ \t]*File "(?P<path>.*?)"(?:, line (?P<line>\d+))?"#;
+
+// Python 3.11+ marks the failing expression beneath the quoted source line, e.g. "    ~~~^^^".
+// The location comes from the preceding frame (see `python::PythonFrameTracker`).
+pub const PYTHON_CARET_LINE_REGEX: &str = r"(?mR)^[ \t]*[~^]*\^[~^]*[ \t]*$";
+
+// IPython 8 traceback frame, e.g. "File ~/proj/module.py:42, in compute(x)"
+const IPYTHON_TRACE_REGEX: &str = r#"(?m)^[ \t]*File (?P<path>[^"\s].*?):(?P<line>\d+)\b"#;
+
+// IPython/Jupyter frame in a notebook cell, e.g. "Cell In[3], line 5". The path group captures
+// the cell's execution count; the file is the configured notebook, if any.
+const IPYTHON_CELL_REGEX: &str = r"(?m)^[ \t]*Cell In\[(?P<cell>\d+)\], line (?P<line>\d+)";
 
 // IPDB traceback pattern (optional line)
 const IPDB_TRACE_REGEX_OPT_LINE: &str = r"^>\s*(?P<path>[^(]+)(?:\((?P<line>\d+)\))?";
 
+// LaTeX error in -file-line-error mode, e.g. "./chapters/intro.tex:42: Undefined control sequence."
+const LATEX_FILE_LINE_ERROR_REGEX: &str =
+    r"(?m)^(?P<path>[^\s:()]+\.(?:tex|sty|cls|ltx|dtx|bib|def|cfg|clo)):(?P<line>\d+)\b";
+
+// LaTeX error location without a file name, e.g. "l.42 \foo". The file is the one currently
+// open in the TeX run.
+const LATEX_LINE_MARKER_REGEX: &str = r"(?m)^l\.(?P<line>\d+)\b";
+
 // Define the raw rule data as a const array
 const RULES_DATA: &[RuleData] = &[
+    // Before FilePath, which would otherwise claim the path alone
+    RuleData {
+        name: "FilePathAnchor",
+        regex_str: FILE_PATH_ANCHOR_REGEX,
+        path_group_name: Some("path"),
+        line_group_name: Some("line"),
+        column_group_name: Some("column"),
+        end_line_group_name: Some("end_line"),
+        end_column_group_name: Some("end_column"),
+    },
+    RuleData {
+        name: "FilePathLines",
+        regex_str: FILE_PATH_LINES_REGEX,
+        path_group_name: Some("path"),
+        line_group_name: Some("line"),
+        column_group_name: None,
+        end_line_group_name: Some("end_line"),
+        end_column_group_name: None,
+    },
     RuleData {
         name: "FilePath",
         regex_str: FILE_PATH_REGEX_OPT_LINE,
-        path_group_name: "path",
+        path_group_name: Some("path"),
         line_group_name: Some("line"),
+        column_group_name: Some("column"),
+        end_line_group_name: Some("end_line"),
+        end_column_group_name: Some("end_column"),
+    },
+    RuleData {
+        name: "QuotedFilePath",
+        regex_str: QUOTED_FILE_PATH_REGEX,
+        path_group_name: Some("path"),
+        line_group_name: Some("line"),
+        column_group_name: None,
+        end_line_group_name: None,
+        end_column_group_name: None,
     },
     RuleData {
         name: "PythonTraceback",
         regex_str: PYTHON_TRACE_REGEX_OPT_LINE,
-        path_group_name: "path",
+        path_group_name: Some("path"),
+        line_group_name: Some("line"),
+        column_group_name: None,
+        end_line_group_name: None,
+        end_column_group_name: None,
+    },
+    RuleData {
+        name: "PythonCaretLine",
+        regex_str: PYTHON_CARET_LINE_REGEX,
+        path_group_name: None,
+        line_group_name: None,
+        column_group_name: None,
+        end_line_group_name: None,
+        end_column_group_name: None,
+    },
+    RuleData {
+        name: "IPythonTraceback",
+        regex_str: IPYTHON_TRACE_REGEX,
+        path_group_name: Some("path"),
+        line_group_name: Some("line"),
+        column_group_name: None,
+        end_line_group_name: None,
+        end_column_group_name: None,
+    },
+    RuleData {
+        name: "IPythonCell",
+        regex_str: IPYTHON_CELL_REGEX,
+        path_group_name: Some("cell"),
         line_group_name: Some("line"),
+        column_group_name: None,
+        end_line_group_name: None,
+        end_column_group_name: None,
     },
     RuleData {
         name: "IpdbTraceback",
         regex_str: IPDB_TRACE_REGEX_OPT_LINE,
-        path_group_name: "path",
+        path_group_name: Some("path"),
         line_group_name: Some("line"),
+        column_group_name: None,
+        end_line_group_name: None,
+        end_column_group_name: None,
+    },
+    RuleData {
+        name: "LatexFileLineError",
+        regex_str: LATEX_FILE_LINE_ERROR_REGEX,
+        path_group_name: Some("path"),
+        line_group_name: Some("line"),
+        column_group_name: None,
+        end_line_group_name: None,
+        end_column_group_name: None,
+    },
+    RuleData {
+        name: "LatexLineMarker",
+        regex_str: LATEX_LINE_MARKER_REGEX,
+        path_group_name: None,
+        line_group_name: Some("line"),
+        column_group_name: None,
+        end_line_group_name: None,
+        end_column_group_name: None,
     },
 ];
 
@@ -73,29 +218,42 @@ lazy_static! {
             let re = Regex::new(rule_data.regex_str).expect("Failed to compile regex");
 
             // Find the capture group index for the path by name
-            let path_group_index = re
-                .capture_names()
-                .position(|name| name == Some(rule_data.path_group_name))
-                .unwrap_or_else(|| panic!("Path capture group '{}' not found in regex for rule '{}'", rule_data.path_group_name, rule_data.name));
+            let path_group_index = rule_data.path_group_name.map(|path_group_name| {
+                re.capture_names()
+                    .position(|name| name == Some(path_group_name))
+                    .unwrap_or_else(|| panic!("Path capture group '{}' not found in regex for rule '{}'", path_group_name, rule_data.name))
+            });
 
             // Find the capture group index for the line number by name, if specified
-            let line_group_index = rule_data.line_group_name.and_then(|name| {
+            let group_index = |group_name: Option<&str>| group_name.and_then(|name| {
                 re.capture_names()
                     .position(|n| n == Some(name))
                     // Log a warning if the named group exists in RuleData but not in regex? For now, just return None.
                     // .or_else(|| { eprintln!("Warning: Optional line group '{}' not found in regex for rule '{}'", name, rule_data.name); None })
             });
+            let line_group_index = group_index(rule_data.line_group_name);
+            let column_group_index = group_index(rule_data.column_group_name);
+            let end_line_group_index = group_index(rule_data.end_line_group_name);
+            let end_column_group_index = group_index(rule_data.end_column_group_name);
 
             CompiledRule {
                 name: rule_data.name,
                 regex: re,
                 path_group_index,
                 line_group_index,
+                column_group_index,
+                end_line_group_index,
+                end_column_group_index,
             }
         }).collect()
     };
 }
 
+/// Whether there is a rule called `name`, for checking rule names in the configuration.
+pub fn is_rule_name(name: &str) -> bool {
+    RULES_DATA.iter().any(|rule_data| rule_data.name == name)
+}
+
 // Returns a slice of the compiled rules.
 pub fn get_compiled_rules() -> &'static [CompiledRule] {
     &COMPILED_RULES
@@ -111,6 +269,12 @@ mod tests {
         assert_eq!(get_compiled_rules().len(), RULES_DATA.len());
     }
 
+    #[test]
+    fn test_is_rule_name() {
+        assert!(is_rule_name("LatexLineMarker"));
+        assert!(!is_rule_name("LatexLineMaker"));
+    }
+
     #[test]
     fn test_rule_compilation_and_content() {
         let rules = get_compiled_rules();
@@ -126,7 +290,7 @@ mod tests {
             .regex
             .capture_names()
             .position(|n| n == Some("line"));
-        assert_eq!(Some(file_rule.path_group_index), path_idx);
+        assert_eq!(file_rule.path_group_index, path_idx);
         assert_eq!(file_rule.line_group_index, line_idx);
     }
 
@@ -175,6 +339,130 @@ mod tests {
         assert!(caps.name("line").is_none()); // Line group should be None
     }
 
+    #[test]
+    fn test_unicode_escaped_and_quoted_paths() {
+        let find_path = |rule_name: &str, text: &str| {
+            let rule = get_compiled_rules()
+                .iter()
+                .find(|r| r.name == rule_name)
+                .unwrap();
+            let caps = rule.regex.captures(text)?;
+            let line = caps.name("line").map(|l| l.as_str().to_string());
+            Some((caps["path"].to_string(), line))
+        };
+        let path_line =
+            |path: &str, line: Option<&str>| Some((path.to_string(), line.map(str::to_string)));
+
+        assert_eq!(
+            find_path("FilePath", "error in src/données/modèle.py:5, aborting"),
+            path_line("src/données/modèle.py", Some("5"))
+        );
+        assert_eq!(
+            find_path(
+                "FilePath",
+                "see ~/Documents/My\\ Project/main.py:3 for details"
+            ),
+            path_line("~/Documents/My\\ Project/main.py", Some("3"))
+        );
+        assert_eq!(
+            find_path("FilePath", "My\\ Project/main.py"),
+            path_line("My\\ Project/main.py", None)
+        );
+        // An unescaped space ends the path
+        assert_eq!(
+            find_path("FilePath", "~/Documents/My Project/main.py:3"),
+            path_line("~/Documents/My", None)
+        );
+
+        assert_eq!(
+            find_path(
+                "QuotedFilePath",
+                "Can't open 'src/dir with space/x.rs':10: denied"
+            ),
+            path_line("'src/dir with space/x.rs'", Some("10"))
+        );
+        assert_eq!(
+            find_path("QuotedFilePath", r#"open "My Project/notes.md" then"#),
+            path_line("\"My Project/notes.md\"", None)
+        );
+        assert_eq!(
+            find_path("QuotedFilePath", "edit `docs/read me.txt`:2 and `make`"),
+            path_line("`docs/read me.txt`", Some("2"))
+        );
+        // Quoted words, and quotes that do not pair up on one line, are left alone
+        assert_eq!(find_path("QuotedFilePath", "unknown option 'build'"), None);
+        assert_eq!(find_path("QuotedFilePath", "it's src/x.rs\nisn't it"), None);
+        assert_eq!(
+            find_path("QuotedFilePath", "'a.rs' and 'b.rs'"),
+            path_line("'a.rs'", None)
+        );
+    }
+
+    #[test]
+    fn test_line_range_regexes() {
+        let groups = |rule_name: &str, text: &str| {
+            let rule = get_compiled_rules()
+                .iter()
+                .find(|r| r.name == rule_name)
+                .unwrap();
+            let caps = rule.regex.captures(text).unwrap();
+            let group = |name| caps.name(name).map(|m| m.as_str().to_string());
+            (
+                caps[0].to_string(),
+                [
+                    group("line"),
+                    group("column"),
+                    group("end_line"),
+                    group("end_column"),
+                ],
+            )
+        };
+        let numbers = |values: [Option<&str>; 4]| values.map(|v| v.map(str::to_string));
+
+        assert_eq!(
+            groups("FilePath", "src/x.rs:10-20 covered"),
+            (
+                "src/x.rs:10-20".to_string(),
+                numbers([Some("10"), None, Some("20"), None])
+            )
+        );
+        assert_eq!(
+            groups("FilePath", "src/x.rs:10:5-20:8: note"),
+            (
+                "src/x.rs:10:5-20:8".to_string(),
+                numbers([Some("10"), Some("5"), Some("20"), Some("8")])
+            )
+        );
+        assert_eq!(
+            groups("FilePathAnchor", "see src/x.rs#L10-L20."),
+            (
+                "src/x.rs#L10-L20".to_string(),
+                numbers([Some("10"), None, Some("20"), None])
+            )
+        );
+        assert_eq!(
+            groups("FilePathAnchor", "src/x.rs#L10C5-L20C8"),
+            (
+                "src/x.rs#L10C5-L20C8".to_string(),
+                numbers([Some("10"), Some("5"), Some("20"), Some("8")])
+            )
+        );
+        assert_eq!(
+            groups("FilePathLines", "in src/x.rs, lines 10 to 20"),
+            (
+                "src/x.rs, lines 10 to 20".to_string(),
+                numbers([Some("10"), None, Some("20"), None])
+            )
+        );
+        assert_eq!(
+            groups("FilePathLines", "docs/index.rst line 7"),
+            (
+                "docs/index.rst line 7".to_string(),
+                numbers([Some("7"), None, None, None])
+            )
+        );
+    }
+
     #[test]
     fn test_python_trace_regex() {
         let rule = get_compiled_rules()
@@ -189,12 +477,46 @@ mod tests {
         assert_eq!(caps.name("path").unwrap().as_str(), "/path/to/my_module.py");
         assert_eq!(caps.name("line").unwrap().as_str(), "123");
 
+        // Frame in the middle of the text
+        let caps = rule
+            .regex
+            .captures("Traceback (most recent call last):\n  File \"/path/x.py\", line 7, in f")
+            .unwrap();
+        assert_eq!(
+            caps.get(0).unwrap().as_str(),
+            "  File \"/path/x.py\", line 7"
+        );
+
         // Without line
         let caps = rule.regex.captures("  File \"/another/path.py\"").unwrap();
         assert_eq!(caps.name("path").unwrap().as_str(), "/another/path.py");
         assert!(caps.name("line").is_none()); // Check optional group by name
     }
 
+    #[test]
+    fn test_ipython_trace_regexes() {
+        let rules = get_compiled_rules();
+        let rule = rules.iter().find(|r| r.name == "IPythonTraceback").unwrap();
+        let caps = rule
+            .regex
+            .captures("KeyError\nFile ~/proj/module.py:42, in compute(x)")
+            .unwrap();
+        assert_eq!(caps.get(0).unwrap().as_str(), "File ~/proj/module.py:42");
+        assert_eq!(caps.name("path").unwrap().as_str(), "~/proj/module.py");
+        assert_eq!(caps.name("line").unwrap().as_str(), "42");
+        // Plain Python frames are left to the PythonTraceback rule
+        assert!(rule.regex.captures("  File \"/x.py\", line 3").is_none());
+
+        let rule = rules.iter().find(|r| r.name == "IPythonCell").unwrap();
+        let caps = rule
+            .regex
+            .captures("Cell In[3], line 5, in <module>")
+            .unwrap();
+        assert_eq!(caps.get(0).unwrap().as_str(), "Cell In[3], line 5");
+        assert_eq!(caps.name("cell").unwrap().as_str(), "3");
+        assert_eq!(caps.name("line").unwrap().as_str(), "5");
+    }
+
     #[test]
     fn test_ipdb_trace_regex() {
         let rule = get_compiled_rules()
@@ -214,4 +536,29 @@ mod tests {
         assert_eq!(caps.name("path").unwrap().as_str(), "/another/script.py");
         assert!(caps.name("line").is_none()); // Check optional group by name
     }
+
+    #[test]
+    fn test_latex_regexes() {
+        let rules = get_compiled_rules();
+        let rule = rules
+            .iter()
+            .find(|r| r.name == "LatexFileLineError")
+            .unwrap();
+        let caps = rule
+            .regex
+            .captures("(./thesis.tex\n./chapters/intro.tex:42: Undefined control sequence.")
+            .unwrap();
+        assert_eq!(caps.name("path").unwrap().as_str(), "./chapters/intro.tex");
+        assert_eq!(caps.name("line").unwrap().as_str(), "42");
+
+        let rule = rules.iter().find(|r| r.name == "LatexLineMarker").unwrap();
+        assert_eq!(rule.path_group_index, None);
+        let caps = rule
+            .regex
+            .captures("! Undefined control sequence.\nl.42 \\foo")
+            .unwrap();
+        assert_eq!(caps.get(0).unwrap().as_str(), "l.42");
+        assert_eq!(caps.name("line").unwrap().as_str(), "42");
+        assert!(rule.regex.captures("see all.42 items").is_none());
+    }
 }
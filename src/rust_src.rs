@@ -0,0 +1,220 @@
+// Panics and backtraces from inside the Rust standard library name files by the path they were
+// compiled at, which never exists locally:
+//
+//   /rustc/90b35a6239c3d8bdabc530a6a0816f7ff89a0aaf/library/core/src/option.rs:2034:5
+//
+// The rust-src component of a rustup toolchain installs the same tree under
+// <toolchain>/lib/rustlib/src/rust/library/. The toolchain built from that commit is preferred,
+// falling back to the active toolchain, whose sources may be of another version: line numbers are
+// then not passed on. Only files already on disk are consulted; rustup itself is never run, as it
+// may try to download a toolchain.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+lazy_static! {
+    static ref RUSTC_PATH_REGEX: Regex =
+        Regex::new(r"^/rustc/(?P<hash>[0-9a-f]{40})/(?P<rest>library/.+)$").unwrap();
+}
+
+#[derive(Debug)]
+pub struct RustSrcLocator {
+    rustup_home: Option<PathBuf>,
+    // rust-src directory for each commit hash seen so far, and whether its toolchain was built
+    // from that commit
+    src_dirs: RefCell<HashMap<String, Option<(PathBuf, bool)>>>,
+}
+
+impl Default for RustSrcLocator {
+    fn default() -> Self {
+        let rustup_home = env::var_os("RUSTUP_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".rustup")));
+        Self::new(rustup_home)
+    }
+}
+
+impl RustSrcLocator {
+    pub fn new(rustup_home: Option<PathBuf>) -> Self {
+        Self {
+            rustup_home,
+            src_dirs: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Where the local rust-src copy of a `/rustc/<hash>/library/...` path would be, or None if
+    /// `path_str` is not such a path or no toolchain has rust-src installed. Whether the file
+    /// exists is left to the caller.
+    pub fn locate(&self, path_str: &str) -> Option<PathBuf> {
+        let caps = RUSTC_PATH_REGEX.captures(path_str)?;
+        let (src_dir, _) = self.src_dir(&caps["hash"])?;
+        Some(src_dir.join(&caps["rest"]))
+    }
+
+    /// Whether line numbers in `path_str` hold for the copy that `locate` finds: false if it is a
+    /// `/rustc/<hash>/` path found in a toolchain built from another commit.
+    pub fn lines_match(&self, path_str: &str) -> bool {
+        match RUSTC_PATH_REGEX.captures(path_str) {
+            Some(caps) => self.src_dir(&caps["hash"]).is_some_and(|(_, exact)| exact),
+            None => true,
+        }
+    }
+
+    fn src_dir(&self, hash: &str) -> Option<(PathBuf, bool)> {
+        self.src_dirs
+            .borrow_mut()
+            .entry(hash.to_string())
+            .or_insert_with(|| self.find_src_dir(hash))
+            .clone()
+    }
+
+    fn find_src_dir(&self, hash: &str) -> Option<(PathBuf, bool)> {
+        let rustup_home = self.rustup_home.as_deref()?;
+        let toolchains: Vec<PathBuf> = fs::read_dir(rustup_home.join("toolchains"))
+            .ok()?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|toolchain| src_dir(toolchain).is_dir())
+            .collect();
+
+        let matching = toolchains.iter().find(|toolchain| {
+            rustc_commit_hash(toolchain).is_some_and(|short_hash| hash.starts_with(&short_hash))
+        });
+        if let Some(toolchain) = matching {
+            return Some((src_dir(toolchain), true));
+        }
+        let active = active_toolchain(rustup_home)?;
+        let toolchain = toolchains.iter().find(|toolchain| {
+            toolchain.file_name().is_some_and(|name| {
+                let name = name.to_string_lossy();
+                name == active || name.starts_with(&format!("{}-", active))
+            })
+        })?;
+        Some((src_dir(toolchain), false))
+    }
+}
+
+fn src_dir(toolchain: &Path) -> PathBuf {
+    toolchain.join("lib/rustlib/src/rust")
+}
+
+// The abbreviated commit hash that a toolchain's rustc was built from, as recorded in the
+// channel manifest that rustup installed it from: `version = "1.95.0 (59807616e 2026-04-14)"`.
+fn rustc_commit_hash(toolchain: &Path) -> Option<String> {
+    let manifest =
+        fs::read_to_string(toolchain.join("lib/rustlib/multirust-channel-manifest.toml")).ok()?;
+    let mut lines = manifest.lines().skip_while(|line| *line != "[pkg.rustc]");
+    let version = lines
+        .find_map(|line| line.strip_prefix("version = "))?
+        .trim_matches('"');
+    let (_, rest) = version.split_once(" (")?;
+    let (short_hash, _) = rest.split_once(' ')?;
+    (short_hash.len() >= 7).then(|| short_hash.to_string())
+}
+
+// The toolchain rustup would use outside any directory override.
+fn active_toolchain(rustup_home: &Path) -> Option<String> {
+    if let Ok(toolchain) = env::var("RUSTUP_TOOLCHAIN") {
+        return Some(toolchain);
+    }
+    let settings = fs::read_to_string(rustup_home.join("settings.toml")).ok()?;
+    let settings: toml::Table = settings.parse().ok()?;
+    Some(settings.get("default_toolchain")?.as_str()?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "59807616e1fa2540724bfbac14d7976d7e4a3860";
+
+    fn make_toolchain(rustup_home: &Path, name: &str, short_hash: &str) -> PathBuf {
+        let toolchain = rustup_home.join("toolchains").join(name);
+        let library = src_dir(&toolchain).join("library/core/src");
+        fs::create_dir_all(&library).unwrap();
+        fs::write(library.join("option.rs"), "").unwrap();
+        fs::write(
+            toolchain.join("lib/rustlib/multirust-channel-manifest.toml"),
+            format!(
+                "[pkg.cargo]\nversion = \"0.96.0 (f2d3ce0bd 2026-03-21)\"\n\n[pkg.rustc]\nversion = \"1.95.0 ({} 2026-04-14)\"\n",
+                short_hash
+            ),
+        )
+        .unwrap();
+        toolchain
+    }
+
+    #[test]
+    fn test_locate_rust_src() {
+        let rustup_home = env::temp_dir().join(format!("xolmis_rustup_{}", std::process::id()));
+        make_toolchain(
+            &rustup_home,
+            "nightly-x86_64-unknown-linux-gnu",
+            "0123abcde",
+        );
+        let stable = make_toolchain(&rustup_home, "stable-x86_64-unknown-linux-gnu", &HASH[..9]);
+        fs::write(
+            rustup_home.join("settings.toml"),
+            "default_toolchain = \"nightly-x86_64-unknown-linux-gnu\"\n",
+        )
+        .unwrap();
+        let locator = RustSrcLocator::new(Some(rustup_home.clone()));
+
+        let option_rs = format!("/rustc/{}/library/core/src/option.rs", HASH);
+        assert_eq!(
+            locator.locate(&option_rs),
+            Some(src_dir(&stable).join("library/core/src/option.rs"))
+        );
+        assert!(locator.lines_match(&option_rs));
+        assert!(locator.lines_match("src/option.rs"));
+        let missing = format!("/rustc/{}/library/core/src/missing.rs", HASH);
+        assert_eq!(
+            locator.locate(&missing),
+            Some(src_dir(&stable).join("library/core/src/missing.rs"))
+        );
+        assert_eq!(
+            locator.locate("/rustc/abc/library/core/src/option.rs"),
+            None
+        );
+        assert_eq!(locator.locate("src/option.rs"), None);
+
+        fs::remove_dir_all(&rustup_home).unwrap();
+    }
+
+    #[test]
+    fn test_fall_back_to_active_toolchain() {
+        let rustup_home =
+            env::temp_dir().join(format!("xolmis_rustup_active_{}", std::process::id()));
+        // Set when run through cargo's rustup proxy, and taking precedence over settings.toml
+        let active = env::var("RUSTUP_TOOLCHAIN").unwrap_or_else(|_| "nightly".to_string());
+        let toolchain = make_toolchain(&rustup_home, &active, "0123abcde");
+        fs::write(
+            rustup_home.join("settings.toml"),
+            format!("default_toolchain = \"{}\"\n", active),
+        )
+        .unwrap();
+        let locator = RustSrcLocator::new(Some(rustup_home.clone()));
+
+        // Its sources may be of another version, so lines in them are not to be trusted
+        let option_rs = format!("/rustc/{}/library/core/src/option.rs", HASH);
+        assert_eq!(
+            locator.locate(&option_rs),
+            Some(src_dir(&toolchain).join("library/core/src/option.rs"))
+        );
+        assert!(!locator.lines_match(&option_rs));
+
+        fs::remove_dir_all(&rustup_home).unwrap();
+    }
+
+    #[test]
+    fn test_rustc_commit_hash() {
+        let rustup_home = env::temp_dir().join(format!("xolmis_rustc_{}", std::process::id()));
+        let toolchain = make_toolchain(&rustup_home, "stable", "59807616e");
+        assert_eq!(rustc_commit_hash(&toolchain).as_deref(), Some("59807616e"));
+        fs::remove_dir_all(&rustup_home).unwrap();
+    }
+}
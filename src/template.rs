@@ -0,0 +1,394 @@
+// URL templates for link targets, e.g. "cursor://file/{path}[:{line}[:{column}]]".
+//
+// {path}, {line}, {column}, {end_line} and {end_column} are replaced by the location's values,
+// the last two when the location is a range such as "src/x.rs:10-20". Text in square brackets is
+// only included when every placeholder inside it has a value, so that one template serves
+// locations with and without a line or column. `\` makes the next character literal, e.g. `\[`.
+//
+// The path is percent-encoded (RFC 3986), leaving only unreserved characters and `/` as they are.
+// That set is valid in the path, query and fragment alike, so `{path}` may appear in any of them:
+// a file named `a#b?c;d` cannot end the path early or add a parameter. Paths containing control
+// characters are not linked at all, and templates may not contain them, as an ESC or BEL in the
+// URL would end the OSC 8 sequence that carries it.
+//
+// Which template applies depends on what the path resolves to: directories have their own, and
+// files may be matched by extension or MIME type, so that images and PDFs need not be sent to a
+// code editor. Line numbers are only passed on for text files.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::str::Chars;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Path,
+    Line,
+    Column,
+    EndLine,
+    EndColumn,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+    Optional(Vec<Part>),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct UrlTemplate {
+    parts: Vec<Part>,
+}
+
+// A position or range within a file, as far as it is known.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    pub end_line: Option<u32>,
+    pub end_column: Option<u32>,
+}
+
+// What a path resolves to, as far as choosing its template goes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileKind {
+    Directory,
+    Text,
+    // Binary files, and anything else that is neither a directory nor a text file
+    Other,
+}
+
+// The values a template is expanded with.
+pub struct LinkValues<'a> {
+    pub path: &'a Path,
+    pub position: Position,
+}
+
+impl LinkValues<'_> {
+    fn get(&self, field: Field) -> Option<String> {
+        let number = match field {
+            Field::Path => return Some(percent_encode(self.path.as_os_str().as_bytes())),
+            Field::Line => self.position.line,
+            Field::Column => self.position.column,
+            Field::EndLine => self.position.end_line,
+            Field::EndColumn => self.position.end_column,
+        };
+        number.map(|n| n.to_string())
+    }
+}
+
+impl UrlTemplate {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let parts = parse_parts(&mut template.chars(), false)?;
+        Ok(Self { parts })
+    }
+
+    /// Expand the template, or None if a placeholder outside square brackets has no value.
+    pub fn expand(&self, values: &LinkValues) -> Option<String> {
+        let mut url = String::new();
+        expand_parts(&self.parts, values, &mut url).then_some(url)
+    }
+}
+
+impl TryFrom<String> for UrlTemplate {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, String> {
+        Self::parse(&template).map_err(|e| format!("invalid URL template {:?}: {}", template, e))
+    }
+}
+
+fn parse_parts(chars: &mut Chars, in_group: bool) -> Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    loop {
+        let Some(c) = chars.next() else {
+            if in_group {
+                return Err("unclosed '['".to_string());
+            }
+            break;
+        };
+        match c {
+            '\\' => literal.push(chars.next().ok_or("trailing '\\'")?),
+            '[' => {
+                flush_literal(&mut literal, &mut parts);
+                parts.push(Part::Optional(parse_parts(chars, true)?));
+            }
+            ']' if in_group => break,
+            '{' => {
+                flush_literal(&mut literal, &mut parts);
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err("unclosed '{'".to_string()),
+                    }
+                }
+                let field = match name.as_str() {
+                    "path" => Field::Path,
+                    "line" => Field::Line,
+                    "column" => Field::Column,
+                    "end_line" => Field::EndLine,
+                    "end_column" => Field::EndColumn,
+                    _ => return Err(format!("unknown placeholder {{{}}}", name)),
+                };
+                parts.push(Part::Field(field));
+            }
+            ']' | '}' => return Err(format!("unmatched '{}'", c)),
+            _ if c.is_control() => return Err(format!("control character {:?}", c)),
+            _ => literal.push(c),
+        }
+    }
+    flush_literal(&mut literal, &mut parts);
+    Ok(parts)
+}
+
+/// Percent-encode every byte but the unreserved characters of RFC 3986 and `/`. Bytes that are
+/// not UTF-8 are encoded like any other, so such paths survive intact.
+pub fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~' | b'/') {
+            encoded.push(byte as char);
+        } else {
+            write!(encoded, "%{:02X}", byte).unwrap();
+        }
+    }
+    encoded
+}
+
+fn flush_literal(literal: &mut String, parts: &mut Vec<Part>) {
+    if !literal.is_empty() {
+        parts.push(Part::Literal(std::mem::take(literal)));
+    }
+}
+
+// Append the expansion of `parts` to `url`, returning false if a placeholder has no value.
+fn expand_parts(parts: &[Part], values: &LinkValues, url: &mut String) -> bool {
+    for part in parts {
+        match part {
+            Part::Literal(text) => url.push_str(text),
+            Part::Field(field) => match values.get(*field) {
+                Some(value) => url.push_str(&value),
+                None => return false,
+            },
+            Part::Optional(group) => {
+                let mut expanded = String::new();
+                if expand_parts(group, values, &mut expanded) {
+                    url.push_str(&expanded);
+                }
+            }
+        }
+    }
+    true
+}
+
+/// The `[links]` section of the configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkTemplates {
+    // Files not matched by extension or MIME type
+    pub file: UrlTemplate,
+    pub directory: UrlTemplate,
+    // By file extension, without the dot, e.g. "svg"
+    pub extensions: HashMap<String, UrlTemplate>,
+    // By MIME type ("application/pdf") or just its top-level type ("image")
+    pub mime_types: HashMap<String, UrlTemplate>,
+}
+
+impl Default for LinkTemplates {
+    fn default() -> Self {
+        let template = |s| UrlTemplate::parse(s).unwrap();
+        let open_with_default_app = ["image", "audio", "video", "application/pdf"]
+            .into_iter()
+            .map(|mime_type| (mime_type.to_string(), template("file://{path}")))
+            .collect();
+        Self {
+            file: template("cursor://file/{path}[:{line}[:{column}]]"),
+            directory: template("file://{path}"),
+            extensions: HashMap::new(),
+            mime_types: open_with_default_app,
+        }
+    }
+}
+
+impl LinkTemplates {
+    /// The URL to link `path` (absolute), which is of the given kind, with, or None if its
+    /// template needs a value that the location lacks, or the path contains control characters.
+    /// The position is dropped unless `path` is a text file.
+    pub fn url(&self, path: &Path, kind: FileKind, position: Position) -> Option<String> {
+        if path.to_string_lossy().chars().any(char::is_control) {
+            return None;
+        }
+        let template = match kind {
+            FileKind::Directory => &self.directory,
+            FileKind::Text | FileKind::Other => self.file_template(path),
+        };
+        template.expand(&LinkValues {
+            path,
+            position: match kind {
+                FileKind::Text => position,
+                FileKind::Directory | FileKind::Other => Position::default(),
+            },
+        })
+    }
+
+    fn file_template(&self, path: &Path) -> &UrlTemplate {
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        let by_extension = extension.and_then(|ext| {
+            self.extensions
+                .iter()
+                .find(|(key, _)| key.to_lowercase() == ext)
+                .map(|(_, template)| template)
+        });
+        let by_mime_type = || {
+            let mime = mime_guess::from_path(path).first()?;
+            self.mime_types
+                .get(mime.essence_str())
+                .or_else(|| self.mime_types.get(mime.type_().as_str()))
+        };
+        by_extension.or_else(by_mime_type).unwrap_or(&self.file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(template: &str, line: Option<u32>, column: Option<u32>) -> Option<String> {
+        let values = LinkValues {
+            path: Path::new("/src/main.rs"),
+            position: Position {
+                line,
+                column,
+                ..Position::default()
+            },
+        };
+        UrlTemplate::parse(template).unwrap().expand(&values)
+    }
+
+    #[test]
+    fn test_expand_template() {
+        let template = "cursor://file/{path}[:{line}[:{column}]]";
+        assert_eq!(
+            expand(template, Some(3), Some(7)).as_deref(),
+            Some("cursor://file//src/main.rs:3:7")
+        );
+        assert_eq!(
+            expand(template, Some(3), None).as_deref(),
+            Some("cursor://file//src/main.rs:3")
+        );
+        assert_eq!(
+            expand(template, None, None).as_deref(),
+            Some("cursor://file//src/main.rs")
+        );
+        assert_eq!(expand("vscode://file{path}:{line}", None, None), None);
+        assert_eq!(
+            expand("x://open?file={path}[&line={line}]\\[1\\]", Some(2), None).as_deref(),
+            Some("x://open?file=/src/main.rs&line=2[1]")
+        );
+    }
+
+    #[test]
+    fn test_expand_range_template() {
+        let template = UrlTemplate::parse("file://{path}[#L{line}[-L{end_line}]]").unwrap();
+        let expand = |line, end_line| {
+            let position = Position {
+                line,
+                end_line,
+                ..Position::default()
+            };
+            template.expand(&LinkValues {
+                path: Path::new("/src/main.rs"),
+                position,
+            })
+        };
+        assert_eq!(
+            expand(Some(10), Some(20)).as_deref(),
+            Some("file:///src/main.rs#L10-L20")
+        );
+        assert_eq!(
+            expand(Some(10), None).as_deref(),
+            Some("file:///src/main.rs#L10")
+        );
+    }
+
+    #[test]
+    fn test_parse_template_errors() {
+        assert!(UrlTemplate::parse("file://{path").is_err());
+        assert!(UrlTemplate::parse("file://{name}").is_err());
+        assert!(UrlTemplate::parse("file://{path}[:{line}").is_err());
+        assert!(UrlTemplate::parse("file://{path}]").is_err());
+        assert!(UrlTemplate::parse("file://{path}\x07").is_err());
+    }
+
+    #[test]
+    fn test_percent_encode_path() {
+        let expand = |template: &str, path: &str| {
+            UrlTemplate::parse(template).unwrap().expand(&LinkValues {
+                path: Path::new(path),
+                position: Position::default(),
+            })
+        };
+        assert_eq!(
+            expand("cursor://file/{path}", "/src/my notes #1?;%.md").as_deref(),
+            Some("cursor://file//src/my%20notes%20%231%3F%3B%25.md")
+        );
+        assert_eq!(
+            expand("x://open?file={path}&y=1", "/a&b=c+d").as_deref(),
+            Some("x://open?file=/a%26b%3Dc%2Bd&y=1")
+        );
+        assert_eq!(
+            expand("file://{path}", "/données/ü~_-.rs").as_deref(),
+            Some("file:///donn%C3%A9es/%C3%BC~_-.rs")
+        );
+        let non_utf8 = std::ffi::OsStr::from_bytes(b"/x\xff");
+        let url = UrlTemplate::parse("file://{path}")
+            .unwrap()
+            .expand(&LinkValues {
+                path: Path::new(non_utf8),
+                position: Position::default(),
+            });
+        assert_eq!(url.as_deref(), Some("file:///x%FF"));
+    }
+
+    #[test]
+    fn test_link_templates() {
+        let dir = Path::new("/src");
+        let text = dir.join("notes.md");
+        let image = dir.join("logo.png");
+        let binary = dir.join("data.bin");
+
+        let mut links = LinkTemplates::default();
+        let position = Position {
+            line: Some(4),
+            ..Position::default()
+        };
+        let url =
+            |links: &LinkTemplates, path: &Path, kind| links.url(path, kind, position).unwrap();
+        assert_eq!(
+            url(&links, &text, FileKind::Text),
+            "cursor://file//src/notes.md:4"
+        );
+        assert_eq!(url(&links, &image, FileKind::Other), "file:///src/logo.png");
+        // No line number for binary files
+        assert_eq!(
+            url(&links, &binary, FileKind::Other),
+            "cursor://file//src/data.bin"
+        );
+        assert_eq!(url(&links, dir, FileKind::Directory), "file:///src");
+
+        links.extensions.insert(
+            "MD".to_string(),
+            UrlTemplate::parse("md://{path}[#L{line}]").unwrap(),
+        );
+        assert_eq!(url(&links, &text, FileKind::Text), "md:///src/notes.md#L4");
+    }
+}
@@ -1,200 +1,573 @@
+use std::iter;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 
 // Use the updated types from the rules module
 use crate::rules::{get_compiled_rules, CompiledRule};
 
-use crate::ansi::iterator::{AnsiElementIterator, Element};
-use crate::ansi::{ansi_preserving_index, strip_ansi_codes};
+use crate::ansi::stream::{Piece, StreamSplitter};
+use crate::ansi::{hyperlink_uri, hyperlink_uri_range, StrippedText};
+use crate::config::Config;
+use crate::directory_stack::DirectoryStack;
+use crate::existing_links::{annotation, parse_file_uri, LinkPolicy};
+use crate::file_index::FileIndex;
+use crate::latex::LatexFileStack;
+use crate::line_check::LineCounter;
+use crate::link_ids::LinkIds;
+use crate::path_filter::PathFilter;
+use crate::python::{caret_column, notebook_cell_line, PythonFrameTracker};
+use crate::resolve::{git_work_tree_root, unquote_path, HomeDirs, PathMap};
+use crate::rust_src::RustSrcLocator;
+use crate::template::Position;
 
 #[derive(Debug)]
 struct MatchInfo<'a> {
     // Start and end character indices in the *stripped* text
     stripped_start: usize,
     stripped_end: usize,
-    // The matched text *without* internal ANSI codes (used for whitespace check)
-    #[allow(dead_code)] // Allow dead code for now, might be used later
+    // The matched text *without* internal ANSI codes
     stripped_text: &'a str,
-    path: &'a str,
-    line: u32,
-    #[allow(dead_code)] // Allow this field to be unused for now
+    // None when the path is implied by context, e.g. the file open in a LaTeX run
+    path: Option<&'a str>,
+    position: Position,
     rule_name: &'static str,
 }
 
-pub fn transform(original_chunk: &str, cwd: &Path) -> String {
-    // Check if the chunk contains the OSC 8 hyperlink introducer.
-    // If it does, return the chunk verbatim to avoid nested links.
-    if original_chunk.contains("\x1b]8;;") {
-        return original_chunk.to_string();
+// Where a match links to
+struct Location {
+    path: PathBuf,
+    position: Position,
+}
+
+// An existing hyperlink that is still open at the end of a chunk
+struct OpenHyperlink {
+    uri: String,
+    // Whether the policy keeps it, rather than stripping it
+    kept: bool,
+}
+
+// A byte range of a chunk, and what to output instead
+type Replacement = ((usize, usize), String);
+
+const HYPERLINK_CLOSE: &str = "\x1b]8;;\x1b\\";
+
+// Longest unterminated line kept between chunks for context tracking. Progress bars and the like
+// can emit a great deal of output without a newline.
+const MAX_PARTIAL_LINE_LEN: usize = 4096;
+
+// Transforms a stream of output chunks, carrying state from one chunk to the next, such as the
+// directories announced by build tools.
+pub struct Transformer {
+    cwd: PathBuf,
+    config: Config,
+    context: OutputContext,
+    rust_src: RustSrcLocator,
+    git_root: Option<PathBuf>,
+    home_dirs: HomeDirs,
+    file_index: Option<FileIndex>,
+    line_counter: LineCounter,
+    link_ids: LinkIds,
+    path_filter: PathFilter,
+    // An existing OSC 8 hyperlink left open by the output so far
+    open_hyperlink: Option<OpenHyperlink>,
+    // Holds back escape sequences split between chunks
+    splitter: StreamSplitter,
+    // Stripped text of the current line, when it was not terminated in the previous chunk.
+    partial_line: String,
+}
+
+// State derived from the lines of output seen so far, used to resolve later matches.
+#[derive(Default)]
+struct OutputContext {
+    directories: DirectoryStack,
+    latex_files: LatexFileStack,
+    python_frames: PythonFrameTracker,
+}
+
+impl OutputContext {
+    fn observe_line(&mut self, line: &str, cwd: &Path) {
+        // Only the text after the last carriage return remains visible on the terminal.
+        let line = line.trim_end_matches('\r');
+        let line = line.rsplit('\r').next().unwrap_or(line);
+        self.directories.observe_line(line, cwd);
+        self.latex_files.observe_line(line);
+        self.python_frames.observe_line(line);
     }
-    let stripped_chunk = strip_ansi_codes(original_chunk);
-    let mut output = String::with_capacity(original_chunk.len());
-    let available_rules = get_compiled_rules();
-    let mut matches = Vec::new();
+}
 
-    // Collect matches based on the stripped chunk
-    for rule in available_rules {
-        collect_matches(rule, &stripped_chunk, &mut matches);
+impl Transformer {
+    #[cfg(test)]
+    pub fn new(cwd: PathBuf) -> Self {
+        Self::with_config(cwd, Config::default())
     }
 
-    // Sort matches by start index in the stripped text
-    matches.sort_by_key(|m| m.stripped_start);
+    pub fn with_config(cwd: PathBuf, config: Config) -> Self {
+        let git_root = git_work_tree_root(&cwd);
+        Self {
+            path_filter: PathFilter::new(&config.filter, git_root.as_deref()),
+            git_root,
+            cwd,
+            config,
+            context: OutputContext::default(),
+            rust_src: RustSrcLocator::default(),
+            home_dirs: HomeDirs::default(),
+            file_index: None,
+            line_counter: LineCounter::default(),
+            link_ids: LinkIds::default(),
+            open_hyperlink: None,
+            splitter: StreamSplitter::default(),
+            partial_line: String::new(),
+        }
+    }
 
-    let mut last_appended_original_byte_end = 0;
-    let mut last_processed_stripped_end = 0;
-    let original_bytes = original_chunk.as_bytes();
+    /// Index the files of the git work tree in the background, so that bare file names and
+    /// elided paths can be resolved.
+    pub fn index_project_files(&mut self) {
+        self.file_index = self.git_root.clone().map(FileIndex::spawn);
+    }
 
-    for m in matches {
-        // Ensure this match (in stripped text space) doesn't overlap with the previous one processed
-        if m.stripped_start < last_processed_stripped_end {
-            continue; // Skip overlapping matches
+    /// Transform the next chunk of output. An escape sequence that the chunk ends in the middle
+    /// of is held back until the rest of it arrives, or is passed on as it is if it is too long.
+    pub fn transform(&mut self, chunk: &str) -> String {
+        let mut output = String::with_capacity(chunk.len());
+        for piece in self.splitter.push(chunk) {
+            match piece {
+                Piece::Complete(text) => output.push_str(&self.transform_complete(&text)),
+                Piece::Raw { text, sequence } => {
+                    let filter = &self.config.escape_filter;
+                    match filter.part_replacement(&sequence, &text) {
+                        Some(replacement) => output.push_str(&replacement),
+                        None => output.push_str(&text),
+                    }
+                }
+            }
         }
+        output
+    }
 
-        // Resolve path and check existence
-        let full_path = resolve_path(cwd, m.path);
-        if !full_path.exists() {
-            last_processed_stripped_end = m.stripped_end; // Mark as processed even if skipped
-            continue; // Skip non-existent paths
+    /// Whatever is held back at the end of the output: a sequence that was never completed.
+    pub fn finish(&mut self) -> String {
+        let held = self.splitter.finish();
+        self.transform_complete(&held)
+    }
+
+    // Transform text containing only whole escape sequences.
+    fn transform_complete(&mut self, original_chunk: &str) -> String {
+        let stripped = StrippedText::new(original_chunk);
+        let stripped_chunk = stripped.text.as_str();
+        // Offset in the stripped chunk up to which lines have been passed to context tracking.
+        let mut context_pos = 0;
+
+        // Text already inside OSC 8 hyperlinks, e.g. from `ls --hyperlink` or gcc, is left alone,
+        // as links cannot be nested, unless the policy for existing links removes them
+        let (protected, mut replacements) = self.existing_hyperlinks(original_chunk, &stripped);
+        for &(start, end) in &stripped.sequences {
+            let sequence = &original_chunk[start..end];
+            if let Some(replacement) = self.config.escape_filter.replacement(sequence) {
+                replacements.push(((start, end), replacement));
+            }
         }
+        replacements.sort_by_key(|((start, _), _)| *start);
+        let mut output = String::with_capacity(original_chunk.len());
+        let available_rules = get_compiled_rules();
+        let mut matches = Vec::new();
 
-        // Find the corresponding byte indices in the original chunk
-        let (original_start, original_end) =
-            match find_original_indices(original_chunk, m.stripped_start, m.stripped_end) {
-                Some(indices) => indices,
-                None => {
-                    // Handle cases where original indices couldn't be found (should be rare)
-                    last_processed_stripped_end = m.stripped_end; // Mark as processed
-                    continue; // Skip if mapping fails
-                }
+        // Collect matches based on the stripped chunk
+        for rule in available_rules {
+            collect_matches(rule, stripped_chunk, &mut matches);
+        }
+
+        // Sort matches by start index in the stripped text
+        matches.sort_by_key(|m| m.stripped_start);
+
+        let mut last_appended_original_byte_end = 0;
+        let mut last_processed_stripped_end = 0;
+        let original_bytes = original_chunk.as_bytes();
+
+        for m in matches {
+            // Ensure this match (in stripped text space) doesn't overlap with the previous one processed
+            if m.stripped_start < last_processed_stripped_end {
+                continue; // Skip overlapping matches
+            }
+            if protected.iter().any(|&(start, end)| {
+                // An empty link counts as covering the character after it
+                start < m.stripped_end && m.stripped_start < end.max(start + 1)
+            }) {
+                continue;
+            }
+
+            // Directory announcements preceding the match must be seen before resolving it
+            self.observe_context(stripped_chunk, &mut context_pos, m.stripped_start);
+
+            // Resolve path and check existence
+            // Non-existent paths are skipped without marking them processed, so that a shorter
+            // match inside them (e.g. a path within quoted prose) can still be linked
+            let link = self
+                .locate(&m)
+                .and_then(|location| self.check_line(m.rule_name, location))
+                .and_then(|location| Some((self.link_url(&location)?, location)));
+            let Some((link_url, location)) = link else {
+                continue;
             };
 
-        // Ensure indices are still valid and ordered relative to the last appended position
-        if !(original_start <= original_end && original_start >= last_appended_original_byte_end) {
-            // Adjusted indices are invalid or overlap incorrectly, skip this match for safety
-            // We might lose a link here, but it prevents panic/corruption.
-            // Consider logging this case if it happens frequently.
-            last_processed_stripped_end = m.stripped_end; // Still mark as processed
-            continue; // Skip invalid/overlapping indices
-        }
+            // Find the corresponding byte indices in the original chunk
+            let (original_start, original_end) =
+                match stripped.original_range(m.stripped_start, m.stripped_end) {
+                    Some(indices) => indices,
+                    None => {
+                        // Handle cases where original indices couldn't be found (should be rare)
+                        last_processed_stripped_end = m.stripped_end; // Mark as processed
+                        continue; // Skip if mapping fails
+                    }
+                };
+
+            // Ensure indices are still valid and ordered relative to the last appended position,
+            // and that the span does not contain part of an existing link
+            if !(original_start <= original_end
+                && original_start >= last_appended_original_byte_end)
+                || original_chunk[original_start..original_end].contains("\x1b]8;")
+            {
+                // Adjusted indices are invalid or overlap incorrectly, skip this match for safety
+                // We might lose a link here, but it prevents panic/corruption.
+                // Consider logging this case if it happens frequently.
+                last_processed_stripped_end = m.stripped_end; // Still mark as processed
+                continue; // Skip invalid/overlapping indices
+            }
 
-        // --- If we reach here, the match is valid and should be linked ---
+            // --- If we reach here, the match is valid and should be linked ---
 
-        let mut link_slice_start = original_start;
+            let mut link_slice_start = original_start;
 
-        // Check for leading newline case
-        if original_start < original_chunk.len()
-            && original_bytes[original_start] == b'\n'
-            && m.stripped_text.starts_with(|c: char| c.is_whitespace())
-        {
-            // Append preceding text *including* the newline
-            output.push_str(&original_chunk[last_appended_original_byte_end..=original_start]);
-            // Start the link slice *after* the newline
-            if original_start + 1 <= original_end {
-                // Avoid panic if end is newline too
-                link_slice_start = original_start + 1;
+            // Check for leading newline case
+            if original_start < original_chunk.len()
+                && original_bytes[original_start] == b'\n'
+                && m.stripped_text.starts_with(|c: char| c.is_whitespace())
+            {
+                // Append preceding text *including* the newline
+                Self::push_replaced(
+                    &mut output,
+                    original_chunk,
+                    &replacements,
+                    last_appended_original_byte_end..original_start + 1,
+                );
+                // Start the link slice *after* the newline
+                if original_start < original_end {
+                    // Avoid panic if end is newline too
+                    link_slice_start = original_start + 1;
+                }
+                // If start+1 > end, the slice will be empty, which is handled below
+            } else {
+                // Append preceding text *excluding* the start offset
+                Self::push_replaced(
+                    &mut output,
+                    original_chunk,
+                    &replacements,
+                    last_appended_original_byte_end..original_start,
+                );
+                // Start link slice at the original start (no change needed)
             }
-            // If start+1 > end, the slice will be empty, which is handled below
-        } else {
-            // Append preceding text *excluding* the start offset
-            output.push_str(&original_chunk[last_appended_original_byte_end..original_start]);
-            // Start link slice at the original start (no change needed)
+
+            // Append the text from the original chunk since the last append point
+            // Get the original text slice, including ANSI codes
+            // Use link_slice_start which might be adjusted past a leading newline
+            // Ensure start <= end before slicing
+            if link_slice_start <= original_end {
+                let mut original_text_slice = String::new();
+                Self::push_replaced(
+                    &mut original_text_slice,
+                    original_chunk,
+                    &replacements,
+                    link_slice_start..original_end,
+                );
+
+                // Format and append hyperlink using the original text slice
+                let (params, link_url) = self.link_ids.link(
+                    &self.config.link_ids,
+                    m.rule_name,
+                    link_url,
+                    &location.path,
+                    location.position.line,
+                );
+                let hyperlinked_text =
+                    format_osc8_hyperlink(&params, &link_url, &original_text_slice);
+                output.push_str(&hyperlinked_text);
+            } else {
+                // Slice would be invalid (start > end), append nothing for the link part
+            }
+
+            // Update state after successful processing
+            last_appended_original_byte_end = original_end;
+            last_processed_stripped_end = m.stripped_end;
         }
 
-        // Append the text from the original chunk since the last append point
-        // Get the original text slice, including ANSI codes
-        // Use link_slice_start which might be adjusted past a leading newline
-        // Ensure start <= end before slicing
-        if link_slice_start <= original_end {
-            let original_text_slice = &original_chunk[link_slice_start..original_end];
-
-            // Format and append hyperlink using the original text slice
-            let link_url = format_cursor_hyperlink(&full_path, m.line);
-            let hyperlinked_text = format_osc8_hyperlink(&link_url, original_text_slice);
-            output.push_str(&hyperlinked_text);
-        } else {
-            // Slice would be invalid (start > end), append nothing for the link part
+        // Append the remaining text from the original chunk after the last match
+        Self::push_replaced(
+            &mut output,
+            original_chunk,
+            &replacements,
+            last_appended_original_byte_end..original_chunk.len(),
+        );
+
+        self.observe_context(stripped_chunk, &mut context_pos, stripped_chunk.len());
+        self.save_partial_line(&stripped_chunk[context_pos..]);
+        output
+    }
+
+    // Append a part of the chunk, with the replacements for the escape sequences in it.
+    fn push_replaced(
+        output: &mut String,
+        original_chunk: &str,
+        replacements: &[Replacement],
+        range: Range<usize>,
+    ) {
+        let mut pos = range.start;
+        for ((start, end), replacement) in replacements {
+            if *start < range.start || *end > range.end {
+                continue;
+            }
+            output.push_str(&original_chunk[pos..*start]);
+            output.push_str(replacement);
+            pos = *end;
         }
+        output.push_str(&original_chunk[pos..range.end]);
+    }
 
-        // Update state after successful processing
-        last_appended_original_byte_end = original_end;
-        last_processed_stripped_end = m.stripped_end;
+    // An OSC 8 sequence with its `file:` URI replaced by one from the link templates.
+    fn rewrite_hyperlink(&self, sequence: &str) -> Option<String> {
+        let uri_range = hyperlink_uri_range(sequence)?;
+        let (path, position) = parse_file_uri(&sequence[uri_range.clone()])?;
+        let path = self.config.path_map.apply(&path);
+        if !self.path_filter.allows(&path) || !path.exists() {
+            return None;
+        }
+        let url = self.link_url(&Location {
+            path: path.into_owned(),
+            position,
+        })?;
+        Some(format!(
+            "{}{}{}",
+            &sequence[..uri_range.start],
+            url,
+            &sequence[uri_range.end..]
+        ))
     }
 
-    // Append the remaining text from the original chunk after the last match
-    output.push_str(&original_chunk[last_appended_original_byte_end..]);
-    output
-}
+    // Apply the policy for existing hyperlinks to those in a chunk. Returns the ranges of the
+    // stripped chunk inside the links that are kept, which must not be linked again, and what to
+    // replace the byte ranges of some of the OSC 8 sequences with. A link may be left open by an
+    // earlier chunk, or left open for the next one.
+    fn existing_hyperlinks(
+        &mut self,
+        original_chunk: &str,
+        stripped: &StrippedText,
+    ) -> (Vec<(usize, usize)>, Vec<Replacement>) {
+        let existing_links = &self.config.existing_links;
+        let annotate = existing_links.policy == LinkPolicy::Annotate;
+        let mut protected = Vec::new();
+        let mut replacements = Vec::new();
+        let mut open = self.open_hyperlink.take().map(|link| (link, 0));
+        for mark in &stripped.hyperlink_marks {
+            let sequence = &original_chunk[mark.sequence.0..mark.sequence.1];
+            let mut replacement = String::new();
+            let mut replaced = false;
 
-// Helper to find original byte indices based on stripped indices
-fn find_original_indices(
-    original_text: &str,
-    stripped_start: usize,
-    stripped_end: usize,
-) -> Option<(usize, usize)> {
-    let original_start_byte = ansi_preserving_index(original_text, stripped_start)?;
-
-    let stripped_len = stripped_end - stripped_start;
-    let mut current_text_len = 0;
-    let mut text_content_end_byte = original_start_byte; // Byte offset where text content ends
-    let mut found_text_end = stripped_len == 0; // If zero length, we are already at the end
-
-    // 1. Find the byte offset where the text content ends
-    if !found_text_end {
-        for element in AnsiElementIterator::new(&original_text[original_start_byte..]) {
-            if let Element::Text(_start, end) = element {
-                let segment_len = end - _start;
-                if current_text_len + segment_len >= stripped_len {
-                    let remaining_len = stripped_len - current_text_len;
-                    text_content_end_byte = original_start_byte + _start + remaining_len;
-                    found_text_end = true;
-                    break; // Found the end of the text content
-                } else {
-                    current_text_len += segment_len;
-                    // Keep track of the end byte of this text segment in case it's the last before match ends
-                    text_content_end_byte = original_start_byte + end;
+            // The open link ends here, whether closed or replaced by another
+            if let Some((link, start)) = open.take() {
+                if link.kept {
+                    protected.push((start, mark.offset));
                 }
-            } else {
-                // If we encounter non-text before finding the text end, update potential end
-                // This handles cases where the match might end *within* ANSI codes (unlikely but possible)
-                let element_end = match element {
-                    Element::Sgr(_, _, end) => end,
-                    Element::Csi(_, end) => end,
-                    Element::Esc(_, end) => end,
-                    Element::Osc(_, end) => end,
-                    Element::Text(_, _) => unreachable!(), // Handled above
+                if link.kept && annotate {
+                    replacement.push_str(if mark.opens {
+                        HYPERLINK_CLOSE
+                    } else {
+                        sequence
+                    });
+                    replacement.push_str(&annotation(&link.uri));
+                    replaced = true;
+                } else if !link.kept && !mark.opens {
+                    replaced = true;
+                }
+            }
+
+            if mark.opens {
+                let uri = hyperlink_uri(sequence).unwrap_or_default();
+                let kept = existing_links.keeps(uri);
+                let rewritten = (kept && existing_links.rewrite_file_uris)
+                    .then(|| self.rewrite_hyperlink(sequence))
+                    .flatten();
+                if let Some(rewritten) = rewritten {
+                    replacement.push_str(&rewritten);
+                    replaced = true;
+                } else if kept && replaced {
+                    replacement.push_str(sequence);
+                }
+                replaced |= !kept;
+                let link = OpenHyperlink {
+                    uri: uri.to_string(),
+                    kept,
                 };
-                text_content_end_byte = original_start_byte + element_end;
+                open = Some((link, mark.offset));
+            }
+
+            if replaced {
+                replacements.push((mark.sequence, replacement));
+            }
+        }
+        if let Some((link, start)) = open {
+            if link.kept {
+                protected.push((start, stripped.text.len()));
+            }
+            self.open_hyperlink = Some(link);
+        }
+        (protected, replacements)
+    }
+
+    // Pass each complete line in `stripped[*pos..until]` to the context trackers, advancing `pos`
+    // past the line's newline.
+    fn observe_context(&mut self, stripped: &str, pos: &mut usize, until: usize) {
+        while let Some(newline) = stripped[*pos..until].find('\n') {
+            let line_end = *pos + newline;
+            if self.partial_line.is_empty() {
+                self.context
+                    .observe_line(&stripped[*pos..line_end], &self.cwd);
+            } else {
+                let mut line = std::mem::take(&mut self.partial_line);
+                line.push_str(&stripped[*pos..line_end]);
+                self.context.observe_line(&line, &self.cwd);
             }
+            *pos = line_end + 1;
         }
     }
 
-    // If we couldn't find the end of the text content (e.g., stripped indices out of bounds)
-    if !found_text_end {
-        return None;
+    fn save_partial_line(&mut self, rest: &str) {
+        if self.partial_line.len() + rest.len() > MAX_PARTIAL_LINE_LEN {
+            self.partial_line.clear();
+        } else {
+            self.partial_line.push_str(rest);
+        }
     }
 
-    // 2. Find the final end byte by consuming trailing ANSI codes
-    let mut final_end_byte = text_content_end_byte;
-    if text_content_end_byte < original_text.len() {
-        for element in AnsiElementIterator::new(&original_text[text_content_end_byte..]) {
-            match element {
-                Element::Text(_, _) => break, // Stop at the next text element
-                Element::Sgr(_, _, end)
-                | Element::Csi(_, end)
-                | Element::Esc(_, end)
-                | Element::Osc(_, end) => {
-                    // Update final end byte to include this ANSI code
-                    final_end_byte = text_content_end_byte + end;
+    // Work out what a match links to. Matches of rules without a path group take their location
+    // from the output preceding them.
+    fn locate(&self, m: &MatchInfo) -> Option<Location> {
+        match m.rule_name {
+            "LatexLineMarker" => Some(Location {
+                path: self.resolve(self.context.latex_files.current_file()?)?,
+                position: Position {
+                    line: Some(m.position.line?),
+                    ..Position::default()
+                },
+            }),
+            "IPythonCell" => {
+                // Only linked when the user has said which notebook is being run
+                let notebook = self.config.notebook.as_deref()?;
+                let execution_count = m.path?.parse().ok()?;
+                let line = notebook_cell_line(notebook, execution_count, m.position.line?)?;
+                Some(Location {
+                    path: notebook.to_path_buf(),
+                    position: Position {
+                        line: Some(line),
+                        ..Position::default()
+                    },
+                })
+            }
+            "PythonCaretLine" => {
+                // The column is linked from the caret line, never from the frame, so that the
+                // links do not depend on where the output was split into chunks
+                let (path_str, line, source_line) =
+                    self.context.python_frames.frame_awaiting_caret_line()?;
+                let path = self.resolve(path_str)?;
+                let column = caret_column(&path, line, source_line, m.stripped_text)?;
+                Some(Location {
+                    path,
+                    position: Position {
+                        line: Some(line),
+                        column: Some(column),
+                        ..Position::default()
+                    },
+                })
+            }
+            _ => {
+                let path = self.resolve(m.path?)?;
+                let mut position = m.position;
+                // Lines in std sources from a toolchain other than the one that printed them
+                // may point anywhere
+                if !self.rust_src.lines_match(m.path?) {
+                    position = Position::default();
                 }
+                Some(Location { path, position })
+            }
+        }
+    }
+
+    // Drop or clamp a location beyond the end of its file, as configured for the rule.
+    fn check_line(&self, rule_name: &str, location: Location) -> Option<Location> {
+        let mode = self.config.line_check.mode_for(rule_name);
+        let position = self
+            .line_counter
+            .check(mode, &location.path, location.position)?;
+        Some(Location {
+            position,
+            ..location
+        })
+    }
+
+    // The URL for a location, from the configured templates.
+    fn link_url(&self, location: &Location) -> Option<String> {
+        // Attempt to get a canonical path, fall back to the resolved absolute path
+        let canonical_path = location
+            .path
+            .canonicalize()
+            .unwrap_or_else(|_| location.path.clone());
+        let kind = self.line_counter.file_kind(&canonical_path)?;
+        self.config
+            .links
+            .url(&canonical_path, kind, location.position)
+    }
+
+    // Resolve a matched path against each of the search bases in turn, taking the first that
+    // names an existing file.
+    fn resolve(&self, path_str: &str) -> Option<PathBuf> {
+        let path_str = unquote_path(path_str);
+        let path_str = path_str.as_ref();
+        if let Some(path) = self.rust_src.locate(path_str) {
+            if !self.path_filter.allows(&path) {
+                return None;
+            }
+            if path.exists() {
+                return Some(path);
             }
         }
+        // A path whose leading directories were elided can only be found in the index
+        if let Some(suffix) = path_str
+            .strip_prefix("…/")
+            .or(path_str.strip_prefix(".../"))
+        {
+            return self.lookup_indexed(suffix);
+        }
+        self.search_bases()
+            .filter_map(|base| resolve_path(base, path_str, &self.home_dirs, &self.config.path_map))
+            .filter(|full_path| self.path_filter.allows(full_path))
+            .find(|full_path| full_path.exists())
+            .or_else(|| self.lookup_indexed(path_str))
     }
 
-    // Basic sanity check
-    if final_end_byte > original_text.len() {
-        return None; // Should not happen if logic is correct
+    // The one project file whose path ends with `suffix`, if the index has been started.
+    fn lookup_indexed(&self, suffix: &str) -> Option<PathBuf> {
+        let path = self.file_index.as_ref()?.lookup(Path::new(suffix))?;
+        // The index may lag behind the filesystem
+        (self.path_filter.allows(&path) && path.exists()).then_some(path)
     }
 
-    Some((original_start_byte, final_end_byte))
+    // The directories relative paths may be relative to, in order of preference: the cwd, the
+    // innermost directory announced by a build tool, the git work tree root (for tools such as
+    // `git diff` that print root-relative paths), then the configured search roots.
+    fn search_bases(&self) -> impl Iterator<Item = &Path> {
+        iter::once(self.cwd.as_path())
+            .chain(self.context.directories.innermost())
+            .chain(self.git_root.as_deref())
+            .chain(self.config.search_roots.iter().map(PathBuf::as_path))
+    }
 }
 
 // Updated helper to use CompiledRule struct and populate MatchInfo correctly
@@ -204,10 +577,31 @@ fn collect_matches<'a>(
     matches: &mut Vec<MatchInfo<'a>>,
 ) {
     for caps in rule.regex.captures_iter(stripped_text_segment) {
-        let line_match = rule.line_group_index.and_then(|idx| caps.get(idx));
-        if let (Some(match_obj), Some(path_match), Some(line_num_match)) =
-            (caps.get(0), caps.get(rule.path_group_index), line_match)
-        {
+        // Rules without a path group take the path from context when the match is resolved
+        let path_match = match rule.path_group_index {
+            Some(idx) => caps.get(idx).map(|m| Some(m.as_str())),
+            None => Some(None),
+        };
+        // A number group that did not participate leaves that number unknown, but one that does
+        // not parse rules out the match
+        let mut numbers_valid = true;
+        let mut number = |group_index: Option<usize>| {
+            let m = group_index.and_then(|idx| caps.get(idx))?;
+            let number = m.as_str().parse::<u32>().ok();
+            numbers_valid &= number.is_some();
+            number
+        };
+        let position = Position {
+            line: number(rule.line_group_index),
+            column: number(rule.column_group_index),
+            end_line: number(rule.end_line_group_index),
+            end_column: number(rule.end_column_group_index),
+        };
+        // Rules with a line group only link matches with a line
+        if !numbers_valid || (rule.line_group_index.is_some() && position.line.is_none()) {
+            continue;
+        }
+        if let (Some(match_obj), Some(mut path)) = (caps.get(0), path_match) {
             // Skip URL-like contexts (e.g., http://example.com)
             if match_obj.start() > 0 {
                 let prev_char = stripped_text_segment.as_bytes()[match_obj.start() - 1];
@@ -215,52 +609,77 @@ fn collect_matches<'a>(
                     continue;
                 }
             }
-            if let Ok(line_num) = line_num_match.as_str().parse::<u32>() {
-                if !path_match.as_str().is_empty() {
-                    matches.push(MatchInfo {
-                        stripped_start: match_obj.start(),
-                        stripped_end: match_obj.end(),
-                        stripped_text: match_obj.as_str(),
-                        path: path_match.as_str(),
-                        line: line_num,
-                        rule_name: rule.name,
-                    });
+            // The path may have picked up punctuation or a closing bracket from the prose around
+            // it. The link then ends with the trimmed path, though a line after it still counts,
+            // as in "[src/lib.rs]:2".
+            let mut match_end = match_obj.end();
+            if let (Some(path_str), true) = (path, rule.name == "FilePath") {
+                let trimmed = trim_trailing_punctuation(path_str);
+                if trimmed.len() < path_str.len() {
+                    match_end = match_obj.start() + trimmed.len();
+                    path = Some(trimmed);
                 }
             }
+            if path != Some("") {
+                matches.push(MatchInfo {
+                    stripped_start: match_obj.start(),
+                    stripped_end: match_end,
+                    stripped_text: &stripped_text_segment[match_obj.start()..match_end],
+                    path,
+                    position,
+                    rule_name: rule.name,
+                });
+            }
         }
     }
 }
 
-// Helper to resolve path relative to cwd or handle absolute paths
-fn resolve_path(cwd: &Path, path_str: &str) -> PathBuf {
-    let path = Path::new(path_str);
-    if path.is_absolute() {
-        path.to_path_buf()
-    } else {
-        // TODO: Consider handling '~' expansion if needed?
-        cwd.join(path)
+// Strip trailing sentence punctuation and unbalanced closing brackets from a path matched in
+// prose, as URL linkifiers do: "(see src/lib.rs)." gives "src/lib.rs", while
+// "app/(group)/page.tsx" is kept whole.
+fn trim_trailing_punctuation(path: &str) -> &str {
+    let mut path = path;
+    while let Some(last) = path.chars().last() {
+        let unbalanced =
+            |open: char, close: char| path.matches(open).count() < path.matches(close).count();
+        let strip = match last {
+            '.' | ',' | ';' | ':' | '!' | '?' => true,
+            ')' => unbalanced('(', ')'),
+            ']' => unbalanced('[', ']'),
+            '}' => unbalanced('{', '}'),
+            _ => false,
+        };
+        if !strip {
+            break;
+        }
+        path = &path[..path.len() - last.len_utf8()];
     }
+    path
 }
 
-// Creates a hyperlink target URL (using custom cursor:// scheme for potential editor integration)
-fn format_cursor_hyperlink(absolute_path: &Path, line: u32) -> String {
-    // Attempt to get a canonical path, fall back to the resolved absolute path
-    let canonical_path = absolute_path
-        .canonicalize()
-        .unwrap_or_else(|_| absolute_path.to_path_buf());
-    // Use to_string_lossy to handle potential non-UTF8 paths gracefully
-    format!(
-        "cursor://file/{}:{}",
-        canonical_path.to_string_lossy(),
-        line
-    )
+// Helper to resolve path relative to cwd or handle absolute paths, after expanding `~` and
+// environment variables, then apply the configured prefix mappings. None if the expansion fails.
+fn resolve_path(
+    cwd: &Path,
+    path_str: &str,
+    home_dirs: &HomeDirs,
+    path_map: &PathMap,
+) -> Option<PathBuf> {
+    let expanded = home_dirs.expand_path(path_str)?;
+    let path = Path::new(expanded.as_ref());
+    let full_path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        cwd.join(path)
+    };
+    Some(path_map.apply(&full_path).into_owned())
 }
 
 // Formats the text with OSC 8 terminal hyperlinks
-fn format_osc8_hyperlink(url: &str, text: &str) -> String {
+fn format_osc8_hyperlink(params: &str, url: &str, text: &str) -> String {
     format!(
-        "]8;;{}\\{}]8;;\\", // Use double backslash for escape sequence in format!
-        url, text
+        "]8;{};{}\\{}]8;;\\", // Use double backslash for escape sequence in format!
+        params, url, text
     )
 }
 
@@ -268,8 +687,19 @@ fn format_osc8_hyperlink(url: &str, text: &str) -> String {
 mod tests {
     use super::*;
     use std::env;
+    use std::os::unix::ffi::OsStrExt;
     use std::path::PathBuf;
 
+    use crate::template::percent_encode;
+
+    // Transforms a single chunk with no state carried over from earlier output.
+    fn transform(original_chunk: &str, cwd: &Path) -> String {
+        let mut transformer = Transformer::new(cwd.to_path_buf());
+        let mut output = transformer.transform(original_chunk);
+        output.push_str(&transformer.finish());
+        output
+    }
+
     // Helper to get the absolute path of a file relative to the crate root
     fn get_crate_abs_path(relative_path: &str) -> PathBuf {
         let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
@@ -281,7 +711,13 @@ mod tests {
 
     // Helper to create the expected cursor:// link format
     fn make_link_url(abs_path: &Path, line: u32) -> String {
-        format!("cursor://file/{}:{}", abs_path.to_string_lossy(), line)
+        let path = percent_encode(abs_path.as_os_str().as_bytes());
+        format!("cursor://file/{}:{}", path, line)
+    }
+
+    // Helper to create the expected link format for a directory
+    fn make_dir_link_url(abs_path: &Path) -> String {
+        format!("file://{}", abs_path.to_string_lossy())
     }
 
     // Helper to format the OSC 8 sequence
@@ -329,6 +765,25 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_python_traceback_multiple_frames() {
+        // Every frame of a traceback arriving in one chunk is linked, not only the first
+        let cargo_toml = get_crate_abs_path("Cargo.toml");
+        let readme = get_crate_abs_path("README.md");
+        let first = format!("  File \"{}\", line 10", cargo_toml.display());
+        let second = format!("  File \"{}\", line 3", readme.display());
+        let input = format!(
+            "Traceback (most recent call last):\r\n{}, in <module>\r\n    main()\r\n{}, in main\r\n    raise ValueError\r\nValueError\r\n",
+            first, second
+        );
+        let expected = format!(
+            "Traceback (most recent call last):\r\n{}, in <module>\r\n    main()\r\n{}, in main\r\n    raise ValueError\r\nValueError\r\n",
+            make_osc8_link(&make_link_url(&cargo_toml, 10), &first),
+            make_osc8_link(&make_link_url(&readme, 3), &second)
+        );
+        assert_eq!(transform(&input, &env::current_dir().unwrap()), expected);
+    }
+
     #[test]
     fn test_ipdb_traceback() {
         // Test with absolute path
@@ -395,6 +850,330 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_env_var_paths() {
+        // cargo sets CARGO_MANIFEST_DIR for test runs, and the crate root is the cwd
+        let input = "See $CARGO_MANIFEST_DIR/src/main.rs:1 and ${CARGO_MANIFEST_DIR}/Cargo.toml:3.";
+        let cwd = env::current_dir().unwrap();
+        let main_url = make_link_url(&get_crate_abs_path("src/main.rs"), 1);
+        let cargo_url = make_link_url(&get_crate_abs_path("Cargo.toml"), 3);
+        let expected = format!(
+            "See {} and {}.",
+            make_osc8_link(&main_url, "$CARGO_MANIFEST_DIR/src/main.rs:1"),
+            make_osc8_link(&cargo_url, "${CARGO_MANIFEST_DIR}/Cargo.toml:3")
+        );
+        assert_eq!(transform(input, &cwd), expected);
+    }
+
+    #[test]
+    fn test_path_map() {
+        // A path printed inside a container whose /app is the crate root
+        let input = "  File \"/app/src/main.rs\", line 1, in <module>\n";
+        let cwd = env::current_dir().unwrap();
+        let config: Config = toml::from_str(&format!(
+            "[path_map]\n\"/app\" = \"{}\"\n",
+            env!("CARGO_MANIFEST_DIR")
+        ))
+        .unwrap();
+        let mut transformer = Transformer::with_config(cwd, config);
+        let url = make_link_url(&get_crate_abs_path("src/main.rs"), 1);
+        let expected = format!(
+            "{}, in <module>\n",
+            make_osc8_link(&url, "  File \"/app/src/main.rs\", line 1")
+        );
+        assert_eq!(transformer.transform(input), expected);
+    }
+
+    #[test]
+    fn test_search_bases() {
+        let dir = env::temp_dir().join(format!("xolmis_bases_{}", std::process::id()));
+        let repo = dir.join("repo");
+        let other = dir.join("other");
+        std::fs::create_dir_all(repo.join(".git")).unwrap();
+        std::fs::create_dir_all(repo.join("sub")).unwrap();
+        std::fs::create_dir_all(repo.join("lib")).unwrap();
+        std::fs::create_dir_all(&other).unwrap();
+        std::fs::write(repo.join("lib/parse.rs"), "").unwrap();
+        std::fs::write(other.join("util.rs"), "").unwrap();
+
+        let config = Config {
+            search_roots: vec![other.clone()],
+            ..Config::default()
+        };
+        let mut transformer = Transformer::with_config(repo.join("sub"), config);
+        let input = "lib/parse.rs:2 util.rs:3 missing.rs:4\n";
+        let expected = format!(
+            "{} {} missing.rs:4\n",
+            make_osc8_link(
+                &make_link_url(&repo.join("lib/parse.rs").canonicalize().unwrap(), 2),
+                "lib/parse.rs:2"
+            ),
+            make_osc8_link(
+                &make_link_url(&other.join("util.rs").canonicalize().unwrap(), 3),
+                "util.rs:3"
+            )
+        );
+        assert_eq!(transformer.transform(input), expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_project_file_index() {
+        let repo = env::temp_dir().join(format!("xolmis_file_index_{}", std::process::id()));
+        std::fs::create_dir_all(repo.join(".git")).unwrap();
+        std::fs::create_dir_all(repo.join("app/models")).unwrap();
+        std::fs::write(repo.join("app/models/user.rb"), "").unwrap();
+
+        let mut transformer = Transformer::new(repo.clone());
+        transformer.index_project_files();
+        let input = "user.rb:12 …/models/user.rb:3 .../models/user.rb:4\n";
+        let url = |line| make_link_url(&repo.join("app/models/user.rb"), line);
+        let expected = format!(
+            "{} {} {}\n",
+            make_osc8_link(&url(12), "user.rb:12"),
+            make_osc8_link(&url(3), "…/models/user.rb:3"),
+            make_osc8_link(&url(4), ".../models/user.rb:4")
+        );
+        // The index is built in the background
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while transformer.transform(input) != expected {
+            assert!(std::time::Instant::now() < deadline);
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn test_paths_with_spaces_quotes_and_unicode() {
+        let dir = env::temp_dir().join(format!("xolmis_spaces_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("My Project")).unwrap();
+        std::fs::create_dir_all(dir.join("données")).unwrap();
+        std::fs::write(dir.join("My Project/main.py"), "").unwrap();
+        std::fs::write(dir.join("données/modèle.py"), "").unwrap();
+        let url = |path: &str, line| make_link_url(&dir.join(path).canonicalize().unwrap(), line);
+
+        let input = "Error: 'My Project/main.py':3 failed, see My\\ Project/main.py:4 and données/modèle.py:5.\n";
+        let expected = format!(
+            "Error: {} failed, see {} and {}.\n",
+            make_osc8_link(&url("My Project/main.py", 3), "'My Project/main.py':3"),
+            make_osc8_link(&url("My Project/main.py", 4), "My\\ Project/main.py:4"),
+            make_osc8_link(&url("données/modèle.py", 5), "données/modèle.py:5")
+        );
+        assert_eq!(transform(input, &dir), expected);
+
+        // A quoted phrase that is not a path does not hide the path inside it
+        let input = "warning: \"could not parse données/modèle.py:5 here\"\n";
+        let expected = format!(
+            "warning: \"could not parse {} here\"\n",
+            make_osc8_link(&url("données/modèle.py", 5), "données/modèle.py:5")
+        );
+        assert_eq!(transform(input, &dir), expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_trim_trailing_punctuation() {
+        assert_eq!(trim_trailing_punctuation("src/main.rs."), "src/main.rs");
+        assert_eq!(trim_trailing_punctuation("src/lib.rs)."), "src/lib.rs");
+        assert_eq!(
+            trim_trailing_punctuation("src/config.toml]"),
+            "src/config.toml"
+        );
+        assert_eq!(trim_trailing_punctuation("src/a.rs,"), "src/a.rs");
+        assert_eq!(
+            trim_trailing_punctuation("app/(group)/page.tsx"),
+            "app/(group)/page.tsx"
+        );
+        assert_eq!(trim_trailing_punctuation("pages/[id]"), "pages/[id]");
+        assert_eq!(trim_trailing_punctuation("pages/[id])"), "pages/[id]");
+        assert_eq!(trim_trailing_punctuation("./"), "./");
+    }
+
+    #[test]
+    fn test_paths_in_prose() {
+        let dir = env::temp_dir().join(format!("xolmis_prose_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("app/(group)/[id]")).unwrap();
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("app/(group)/[id]/page.tsx"), "").unwrap();
+        std::fs::write(dir.join("src/lib.rs"), "").unwrap();
+        std::fs::write(dir.join("config.toml"), "").unwrap();
+        let link = |path: &str, line, text: &str| {
+            let url = make_link_url(&dir.join(path).canonicalize().unwrap(), line);
+            make_osc8_link(&url, text)
+        };
+        let input = "See src/lib.rs:10. (src/lib.rs:5) [config.toml:2] src/lib.rs:3, \
+                     and (app/(group)/[id]/page.tsx:7).\n";
+        let expected = format!(
+            "See {}. ({}) [{}] {}, and ({}).\n",
+            link("src/lib.rs", 10, "src/lib.rs:10"),
+            link("src/lib.rs", 5, "src/lib.rs:5"),
+            link("config.toml", 2, "config.toml:2"),
+            link("src/lib.rs", 3, "src/lib.rs:3"),
+            link(
+                "app/(group)/[id]/page.tsx",
+                7,
+                "app/(group)/[id]/page.tsx:7"
+            )
+        );
+        assert_eq!(transform(input, &dir), expected);
+
+        // Punctuation and brackets between a path and its line are trimmed from the path
+        let input =
+            "(see src/lib.rs:3). [src/lib.rs]:2 src/lib.rs.:4 (app/(group)/[id]/page.tsx):7\n";
+        let expected = format!(
+            "(see {}). [{}]:2 {}.:4 ({}):7\n",
+            link("src/lib.rs", 3, "src/lib.rs:3"),
+            link("src/lib.rs", 2, "src/lib.rs"),
+            link("src/lib.rs", 4, "src/lib.rs"),
+            link("app/(group)/[id]/page.tsx", 7, "app/(group)/[id]/page.tsx")
+        );
+        assert_eq!(transform(input, &dir), expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_link_targets_by_file_type() {
+        let dir = env::temp_dir().join(format!("xolmis_targets_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("assets")).unwrap();
+        std::fs::write(dir.join("assets/logo.png"), b"\x89PNG\r\n\x1a\n\0\0").unwrap();
+        std::fs::write(dir.join("assets/notes.txt"), "notes\n").unwrap();
+        let abs = |path: &str| dir.join(path).canonicalize().unwrap();
+
+        // A directory and an image get no line number, text files do
+        let input = "./assets:2 has assets/logo.png:3 and assets/notes.txt:1\n";
+        let expected = format!(
+            "{} has {} and {}\n",
+            make_osc8_link(&make_dir_link_url(&abs("assets")), "./assets:2"),
+            make_osc8_link(
+                &format!("file://{}", abs("assets/logo.png").display()),
+                "assets/logo.png:3"
+            ),
+            make_osc8_link(
+                &make_link_url(&abs("assets/notes.txt"), 1),
+                "assets/notes.txt:1"
+            )
+        );
+        assert_eq!(transform(input, &dir), expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_line_ranges() {
+        let cwd = env::current_dir().unwrap();
+        let config: Config =
+            toml::from_str("[links]\nfile = \"file://{path}[#L{line}[-L{end_line}]]\"\n").unwrap();
+        let mut transformer = Transformer::with_config(cwd, config);
+        let main_rs = get_crate_abs_path("src/main.rs");
+        let link = |fragment: &str, text: &str| {
+            let url = format!("file://{}#{}", main_rs.display(), fragment);
+            make_osc8_link(&url, text)
+        };
+        let input = "src/main.rs:10-20, src/main.rs#L3-L4 and src/main.rs, lines 5 to 6\n";
+        let expected = format!(
+            "{}, {} and {}\n",
+            link("L10-L20", "src/main.rs:10-20"),
+            link("L3-L4", "src/main.rs#L3-L4"),
+            link("L5-L6", "src/main.rs, lines 5 to 6")
+        );
+        assert_eq!(transformer.transform(input), expected);
+    }
+
+    #[test]
+    fn test_path_filter() {
+        let dir = env::temp_dir().join(format!("xolmis_path_filter_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::create_dir_all(dir.join("target/debug")).unwrap();
+        std::fs::write(dir.join("src/lib.rs"), "").unwrap();
+        std::fs::write(dir.join("target/debug/out.rs"), "").unwrap();
+        let input = "src/lib.rs:1 target/debug/out.rs:2\n";
+
+        let config: Config = toml::from_str("[filter]\nexclude = [\"target\"]\n").unwrap();
+        let mut transformer = Transformer::with_config(dir.clone(), config);
+        let expected = format!(
+            "{} target/debug/out.rs:2\n",
+            make_osc8_link(&make_link_url(&dir.join("src/lib.rs"), 1), "src/lib.rs:1")
+        );
+        assert_eq!(transformer.transform(input), expected);
+
+        let config: Config = toml::from_str("[filter]\ninclude = [\"target/**\"]\n").unwrap();
+        let mut transformer = Transformer::with_config(dir.clone(), config);
+        let expected = format!(
+            "src/lib.rs:1 {}\n",
+            make_osc8_link(
+                &make_link_url(&dir.join("target/debug/out.rs"), 2),
+                "target/debug/out.rs:2"
+            )
+        );
+        assert_eq!(transformer.transform(input), expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_line_check() {
+        let path = env::temp_dir().join(format!("xolmis_line_check_{}.txt", std::process::id()));
+        std::fs::write(&path, "one\ntwo\nthree\n").unwrap();
+        let input = format!("{}:2 {}:900\n", path.display(), path.display());
+        let text = |line| format!("{}:{}", path.display(), line);
+
+        // Off by default
+        let expected = format!(
+            "{} {}\n",
+            make_osc8_link(&make_link_url(&path, 2), &text(2)),
+            make_osc8_link(&make_link_url(&path, 900), &text(900))
+        );
+        assert_eq!(transform(&input, &env::temp_dir()), expected);
+
+        let config: Config = toml::from_str("[line_check]\nmode = \"drop\"\n").unwrap();
+        let mut transformer = Transformer::with_config(env::temp_dir(), config);
+        let expected = format!(
+            "{} {}\n",
+            make_osc8_link(&make_link_url(&path, 2), &text(2)),
+            text(900)
+        );
+        assert_eq!(transformer.transform(&input), expected);
+
+        let config: Config =
+            toml::from_str("[line_check]\nmode = \"drop\"\nrules = { FilePath = \"clamp\" }\n")
+                .unwrap();
+        let mut transformer = Transformer::with_config(env::temp_dir(), config);
+        let expected = format!(
+            "{} {}\n",
+            make_osc8_link(&make_link_url(&path, 2), &text(2)),
+            make_osc8_link(&make_link_url(&path, 3), &text(900))
+        );
+        assert_eq!(transformer.transform(&input), expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_hostile_filenames() {
+        let dir = env::temp_dir().join(format!("xolmis_hostile_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let traceback = |name: &str| format!("  File \"{}/{}\", line 3\n", dir.display(), name);
+
+        // Characters that would end the path or the URL are percent-encoded
+        for (name, encoded) in [
+            ("a b.py", "a%20b.py"),
+            ("x#y?z=1&w.py", "x%23y%3Fz%3D1%26w.py"),
+            ("100%;.py", "100%25%3B.py"),
+            ("quote'(1).py", "quote%27%281%29.py"),
+        ] {
+            std::fs::write(dir.join(name), "").unwrap();
+            let input = traceback(name);
+            let url = format!("cursor://file/{}/{}:3", dir.display(), encoded);
+            let expected = format!("{}\n", make_osc8_link(&url, input.trim_end()));
+            assert_eq!(transform(&input, &dir), expected, "{:?}", name);
+        }
+
+        // Names with control characters are left unlinked
+        for name in ["bel\x07.py", "del\x7f.py", "csi\u{9b}.py"] {
+            std::fs::write(dir.join(name), "").unwrap();
+            let input = traceback(name);
+            assert_eq!(transform(&input, &dir), input, "{:?}", name);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_non_existent_path() {
         // This path:line should not be linked because the file doesn't exist
@@ -407,33 +1186,523 @@ mod tests {
     }
 
     #[test]
-    #[ignore = "known limitation: ANSI codes inside match break pattern recognition"]
+    fn test_build_tool_directory_fallback() {
+        let cwd = env::current_dir().unwrap();
+        let src_dir = get_crate_abs_path("src");
+        let abs_path = get_crate_abs_path("src/ansi/mod.rs");
+        let url = make_link_url(&abs_path, 7);
+        let announcement = format!("make[1]: Entering directory '{}'\n", src_dir.display());
+        let input = format!("{}ansi/mod.rs:7: error", announcement);
+        let expected = format!(
+            "{}{}: error",
+            announcement,
+            make_osc8_link(&url, "ansi/mod.rs:7")
+        );
+        assert_eq!(transform(&input, &cwd), expected);
+
+        // Without the announcement the path does not resolve against the cwd.
+        let input = "ansi/mod.rs:7: error";
+        assert_eq!(transform(input, &cwd), input);
+    }
+
+    #[test]
+    fn test_build_tool_directory_across_chunks() {
+        let cwd = env::current_dir().unwrap();
+        let src_dir = get_crate_abs_path("src");
+        let abs_path = get_crate_abs_path("src/ansi/mod.rs");
+        let url = make_link_url(&abs_path, 7);
+        let mut transformer = Transformer::new(cwd);
+
+        // The announcement is split across two chunks.
+        let first = "make[1]: Entering dir";
+        assert_eq!(transformer.transform(first), first);
+        let second = format!("ectory '{}'\n", src_dir.display());
+        assert_eq!(transformer.transform(&second), second);
+        assert_eq!(
+            transformer.transform("ansi/mod.rs:7"),
+            make_osc8_link(&url, "ansi/mod.rs:7")
+        );
+
+        let leaving = format!("make[1]: Leaving directory '{}'\n", src_dir.display());
+        assert_eq!(transformer.transform(&leaving), leaving);
+        assert_eq!(transformer.transform("ansi/mod.rs:7"), "ansi/mod.rs:7");
+    }
+
+    #[test]
+    fn test_latex_line_marker() {
+        // Stand-ins for .tex files: the existence check is all that matters.
+        let cwd = env::current_dir().unwrap();
+        let main_path = get_crate_abs_path("src/main.rs");
+        let mod_path = get_crate_abs_path("src/ansi/mod.rs");
+        let mut transformer = Transformer::new(cwd);
+
+        let preamble = "This is pdfTeX, Version 3.141592653-2.6-1.40.25 (TeX Live 2023)\n(./src/main.rs\n(./src/ansi/mod.rs\n";
+        assert_eq!(transformer.transform(preamble), preamble);
+        // TeX quotes the source line in two parts, split at the point of the error.
+        let error = "! Undefined control sequence.\nl.42 \\foo\n         )\n";
+        let expected = format!(
+            "! Undefined control sequence.\n{} \\foo\n         )\n",
+            make_osc8_link(&make_link_url(&mod_path, 42), "l.42")
+        );
+        assert_eq!(transformer.transform(error), expected);
+
+        // The parenthesis in the quoted source above did not close the file; this one does.
+        let input = ")\nl.7 \\bar\n";
+        let expected = format!(
+            ")\n{} \\bar\n",
+            make_osc8_link(&make_link_url(&main_path, 7), "l.7")
+        );
+        assert_eq!(transformer.transform(input), expected);
+
+        // Outside a TeX run there is no file to link to.
+        let input = "\nTranscript written on thesis.log.\nl.7 \\bar";
+        assert_eq!(transformer.transform(input), input);
+    }
+
+    #[test]
+    fn test_python_traceback_caret_column() {
+        let cwd = env::current_dir().unwrap();
+        let path =
+            env::temp_dir().join(format!("xolmis_transform_caret_{}.py", std::process::id()));
+        std::fs::write(&path, "def f(region):\n    return shipping[region]\n").unwrap();
+        let abs_path = path.canonicalize().unwrap();
+        let frame = format!("  File \"{}\", line 2", path.display());
+        let source_line = "\n    return shipping[region]\n";
+        let caret_line = "           ~~~~~~~~^^^^^^^^";
+        let url = format!("{}:12", make_link_url(&abs_path, 2));
+
+        // The frame links to the line, and the caret line to the column in it, whether the
+        // traceback arrives in one chunk or one line at a time
+        let input = format!(
+            "{}, in f{}{}\r\nKeyError: 'eu'\r\n",
+            frame, source_line, caret_line
+        );
+        let expected = format!(
+            "{}, in f{}{}\r\nKeyError: 'eu'\r\n",
+            make_osc8_link(&make_link_url(&abs_path, 2), &frame),
+            source_line,
+            make_osc8_link(&url, caret_line)
+        );
+        assert_eq!(transform(&input, &cwd), expected);
+        let mut transformer = Transformer::new(cwd);
+        let output: String = input
+            .split_inclusive('\n')
+            .map(|line| transformer.transform(line))
+            .collect();
+        assert_eq!(output, expected);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_link_ids() {
+        let cwd = env::current_dir().unwrap();
+        let path = env::temp_dir().join(format!("xolmis_link_ids_{}.py", std::process::id()));
+        std::fs::write(&path, "def f(region):\n    return shipping[region]\n").unwrap();
+        let abs_path = path.canonicalize().unwrap();
+        let frame = format!("  File \"{}\", line 2", path.display());
+        let caret_line = "           ~~~~~~~~^^^^^^^^";
+        let link = |id: &str, url: &str, text: &str| {
+            format!("\x1b]8;id={};{}\x1b\\{}\x1b]8;;\x1b\\", id, url, text)
+        };
+        let id_of = |output: &str| {
+            let start = output.find("id=").unwrap() + 3;
+            output[start..start + output[start..].find(';').unwrap()].to_string()
+        };
+
+        // Each link has its own id, even to the same place
+        let config: Config = toml::from_str("[link_ids]\nenabled = true\n").unwrap();
+        let mut transformer = Transformer::with_config(cwd.clone(), config);
+        let first = transformer.transform(&format!("{}:1\n", path.display()));
+        let second = transformer.transform(&format!("{}:1\n", path.display()));
+        assert!(id_of(&first).starts_with("xl1-"));
+        assert!(id_of(&second).starts_with("xl2-"));
+        let url = make_link_url(&abs_path, 1);
+        let text = format!("{}:1", path.display());
+        assert_eq!(first, format!("{}\n", link(&id_of(&first), &url, &text)));
+
+        // A frame and its caret line share an id and the frame's URL
+        let config: Config = toml::from_str(
+            "[link_ids]\nenabled = true\nshared_rules = [\"PythonTraceback\", \"PythonCaretLine\"]\n",
+        )
+        .unwrap();
+        let mut transformer = Transformer::with_config(cwd, config);
+        let url = make_link_url(&abs_path, 2);
+        let first =
+            transformer.transform(&format!("{}, in f\n    return shipping[region]\n", frame));
+        let id = id_of(&first);
+        assert_eq!(
+            first,
+            format!(
+                "{}, in f\n    return shipping[region]\n",
+                link(&id, &url, &frame)
+            )
+        );
+        assert_eq!(
+            transformer.transform(&format!("{}\n", caret_line)),
+            format!("{}\n", link(&id, &url, caret_line))
+        );
+
+        // Ids are off by default
+        assert_eq!(
+            transform(&format!("{}:1\n", path.display()), &env::temp_dir()),
+            format!("{}\n", make_osc8_link(&make_link_url(&abs_path, 1), &text))
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_ipython_traceback() {
+        let cwd = env::current_dir().unwrap();
+        let abs_path = get_crate_abs_path("Cargo.toml");
+        let frame = format!("File {}:5", abs_path.display());
+        let input = format!("ZeroDivisionError\n{}, in compute(x)\n", frame);
+        let expected = format!(
+            "ZeroDivisionError\n{}, in compute(x)\n",
+            make_osc8_link(&make_link_url(&abs_path, 5), &frame)
+        );
+        assert_eq!(transform(&input, &cwd), expected);
+    }
+
+    #[test]
+    fn test_ipython_cell() {
+        let cwd = env::current_dir().unwrap();
+        let input = "Cell In[3], line 2\n      1 x = 0\n----> 2 math.log(x)\n";
+        // Without a configured notebook the cell frame is left alone
+        assert_eq!(transform(input, &cwd), input);
+
+        let notebook =
+            env::temp_dir().join(format!("xolmis_transform_{}.ipynb", std::process::id()));
+        std::fs::write(
+            &notebook,
+            "{\n \"cells\": [\n  {\n   \"cell_type\": \"code\",\n   \"execution_count\": 3,\n   \"id\": \"b2\",\n   \"metadata\": {},\n   \"outputs\": [],\n   \"source\": [\n    \"x = 0\\n\",\n    \"math.log(x)\"\n   ]\n  }\n ]\n}\n",
+        )
+        .unwrap();
+        let config = Config {
+            notebook: Some(notebook.clone()),
+            ..Config::default()
+        };
+        let mut transformer = Transformer::with_config(cwd, config);
+        let url = make_link_url(&notebook.canonicalize().unwrap(), 11);
+        let expected = format!(
+            "{}\n      1 x = 0\n----> 2 math.log(x)\n",
+            make_osc8_link(&url, "Cell In[3], line 2")
+        );
+        assert_eq!(transformer.transform(input), expected);
+        std::fs::remove_file(&notebook).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_home_relative_path() {
+        let cwd = env::current_dir().unwrap();
+        let home_dirs = HomeDirs::default();
+        let resolve = |path_str| resolve_path(&cwd, path_str, &home_dirs, &PathMap::default());
+        let home = PathBuf::from(env::var("HOME").unwrap());
+        assert_eq!(
+            resolve("~/proj/module.py"),
+            Some(home.join("proj/module.py"))
+        );
+        let root_home = nix::unistd::User::from_name("root").unwrap().unwrap().dir;
+        assert_eq!(resolve("~root/x.py"), Some(root_home.join("x.py")));
+        // Unknown users and unset variables are not guessed at
+        assert_eq!(resolve("~no_such_user_xolmis/x.py"), None);
+        assert_eq!(resolve("$NO_SUCH_VAR_XOLMIS/x.py"), None);
+    }
+
+    #[test]
+    fn test_rust_std_paths() {
+        let rustup_home =
+            env::temp_dir().join(format!("xolmis_transform_rustup_{}", std::process::id()));
+        let active = env::var("RUSTUP_TOOLCHAIN").unwrap_or_else(|_| "stable".to_string());
+        let src_file = rustup_home
+            .join("toolchains")
+            .join(&active)
+            .join("lib/rustlib/src/rust/library/core/src/option.rs");
+        std::fs::create_dir_all(src_file.parent().unwrap()).unwrap();
+        std::fs::write(&src_file, "").unwrap();
+        std::fs::write(
+            rustup_home.join(format!(
+                "toolchains/{}/lib/rustlib/multirust-channel-manifest.toml",
+                active
+            )),
+            "[pkg.rustc]\nversion = \"1.95.0 (59807616e 2026-04-14)\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            rustup_home.join("settings.toml"),
+            format!("default_toolchain = \"{}\"\n", active),
+        )
+        .unwrap();
+        let src_file = src_file.canonicalize().unwrap();
+        let mut transformer = Transformer::new(env::current_dir().unwrap());
+        transformer.rust_src = RustSrcLocator::new(Some(rustup_home.clone()));
+
+        // A panic in std built from the installed toolchain's commit
+        let frame =
+            "/rustc/59807616e1fa2540724bfbac14d7976d7e4a3860/library/core/src/option.rs:2034:5";
+        let url = format!("{}:5", make_link_url(&src_file, 2034));
+        let input = format!("  at {}\n", frame);
+        let expected = format!("  at {}\n", make_osc8_link(&url, frame));
+        assert_eq!(transformer.transform(&input), expected);
+
+        // From another commit: the active toolchain's copy may be of another version, so the
+        // link leaves out the line and column
+        let frame =
+            "/rustc/0000000000000000000000000000000000000000/library/core/src/option.rs:2034:5";
+        let url = format!(
+            "cursor://file/{}",
+            percent_encode(src_file.as_os_str().as_bytes())
+        );
+        let input = format!("  at {}\n", frame);
+        let expected = format!("  at {}\n", make_osc8_link(&url, frame));
+        assert_eq!(transformer.transform(&input), expected);
+
+        std::fs::remove_dir_all(&rustup_home).unwrap();
+    }
+
+    #[test]
+    fn test_existing_hyperlinks() {
+        let cwd = env::current_dir().unwrap();
+        let main_rs = get_crate_abs_path("src/main.rs");
+        let cargo_toml = get_crate_abs_path("Cargo.toml");
+        let existing = make_osc8_link("file:///elsewhere/Cargo.toml", "Cargo.toml:1");
+        let input = format!("{} src/main.rs:2 Cargo.toml:3\n", existing);
+        let expected = format!(
+            "{} {} {}\n",
+            existing,
+            make_osc8_link(&make_link_url(&main_rs, 2), "src/main.rs:2"),
+            make_osc8_link(&make_link_url(&cargo_toml, 3), "Cargo.toml:3")
+        );
+        assert_eq!(transform(&input, &cwd), expected);
+
+        // A link opened in one chunk protects the next chunk up to where it is closed
+        let mut transformer = Transformer::new(cwd.clone());
+        let first = "\x1b]8;id=7;file:///elsewhere/\x07see src/main.rs:1";
+        let second = " and Cargo.toml:1\x1b]8;;\x07 then Cargo.toml:2\n";
+        assert_eq!(transformer.transform(first), first);
+        assert_eq!(
+            transformer.transform(second),
+            format!(
+                " and Cargo.toml:1\x1b]8;;\x07 then {}\n",
+                make_osc8_link(&make_link_url(&cargo_toml, 2), "Cargo.toml:2")
+            )
+        );
+
+        // A match cannot take in an empty link
+        let input = "src/main.rs:\x1b]8;;file:///x\x1b\\\x1b]8;;\x1b\\4\n";
+        assert_eq!(transform(input, &cwd), input);
+    }
+
+    #[test]
+    fn test_image_payloads_not_linked() {
+        let cwd = env::current_dir().unwrap();
+        let link = make_osc8_link(
+            &make_link_url(&get_crate_abs_path("src/main.rs"), 9),
+            "src/main.rs:9",
+        );
+        for image in [
+            "\x1b_Gf=100,a=T;src/main.rs:1/iVBORw0KGgo=\x1b\\",
+            "\x1bPq#0;2;0;0;0#0~~-src/main.rs:2\x1b\\",
+            "\x1bPtmux;\x1b\x1b_Gf=100;src/main.rs:3\x1b\x1b\\\x1b\\",
+            "\x1bPtmux;\x1b\x1b[31msrc/main.rs:4\x1b\\",
+        ] {
+            let input = format!("{} src/main.rs:9\n", image);
+            let expected = format!("{} {}\n", image, link);
+            assert_eq!(transform(&input, &cwd), expected, "{:?}", image);
+        }
+    }
+
+    #[test]
+    fn test_sequences_split_between_chunks() {
+        let cwd = env::current_dir().unwrap();
+        let main_rs = get_crate_abs_path("src/main.rs");
+
+        // Half an SGR is held back, rather than being taken for text
+        let mut transformer = Transformer::new(cwd.clone());
+        assert_eq!(transformer.transform("see \x1b[3"), "see ");
+        assert_eq!(
+            transformer.transform("1msrc/main.rs:2\x1b[0m\n"),
+            format!(
+                "\x1b[31m{}\n",
+                make_osc8_link(&make_link_url(&main_rs, 2), "src/main.rs:2\x1b[0m")
+            )
+        );
+
+        // The rest of an OSC 8 URL is not linked
+        let mut transformer = Transformer::new(cwd.clone());
+        let first = "\x1b]8;;https://example.com/";
+        let second = "src/main.rs:3\x1b\\docs\x1b]8;;\x1b\\\n";
+        assert_eq!(transformer.transform(first), "");
+        assert_eq!(
+            transformer.transform(second),
+            format!("{}{}", first, second)
+        );
+
+        // So is the rest of a tmux passthrough string, in which ESC is doubled
+        let mut transformer = Transformer::new(cwd.clone());
+        let first = "\x1bPtmux;\x1b\x1b]8;;x\x07";
+        let second = "src/main.rs:4\x1b\x1b]8;;\x07\x1b\\\n";
+        assert_eq!(transformer.transform(first), "");
+        assert_eq!(
+            transformer.transform(second),
+            format!("{}{}", first, second)
+        );
+
+        // An unfinished sequence is output at the end
+        let mut transformer = Transformer::new(cwd);
+        assert_eq!(transformer.transform("done\x1b]0;ti"), "done");
+        assert_eq!(transformer.finish(), "\x1b]0;ti");
+    }
+
+    #[test]
+    fn test_rewrite_existing_file_uris() {
+        let cwd = env::current_dir().unwrap();
+        let main_rs = get_crate_abs_path("src/main.rs");
+        let text = "\x1b[1;34mmain.rs\x1b[0m";
+        let existing = |uri: &str| format!("\x1b]8;id=3;{}\x1b\\{}\x1b]8;;\x1b\\", uri, text);
+        let input = format!(
+            "{} {} {}\n",
+            existing(&format!("file://localhost{}#L7", main_rs.display())),
+            existing(&format!("file://{}", main_rs.display())),
+            existing("file:///no/such/file.rs")
+        );
+        // Left alone by default
+        assert_eq!(transform(&input, &cwd), input);
+
+        let config: Config =
+            toml::from_str("[existing_links]\nrewrite_file_uris = true\n").unwrap();
+        let mut transformer = Transformer::with_config(cwd.clone(), config);
+        let expected = format!(
+            "{} {} {}\n",
+            existing(&make_link_url(&main_rs, 7)),
+            existing(&format!("cursor://file/{}", main_rs.display())),
+            existing("file:///no/such/file.rs")
+        );
+        assert_eq!(transformer.transform(&input), expected);
+
+        // Nor are links to files that the filter excludes
+        let config: Config = toml::from_str(
+            "[existing_links]\nrewrite_file_uris = true\n[filter]\nexclude = [\"main.rs\"]\n",
+        )
+        .unwrap();
+        let mut transformer = Transformer::with_config(cwd.clone(), config);
+        assert_eq!(transformer.transform(&input), input);
+    }
+
+    #[test]
+    fn test_existing_link_policy() {
+        let cwd = env::current_dir().unwrap();
+        let main_rs = get_crate_abs_path("src/main.rs");
+        let input = format!(
+            "{} {}\n",
+            make_osc8_link("https://evil.example/", "src/main.rs:3"),
+            make_osc8_link("https://github.com/", "GitHub")
+        );
+        let transform_with = |toml: &str| {
+            let config: Config = toml::from_str(toml).unwrap();
+            Transformer::with_config(cwd.clone(), config).transform(&input)
+        };
+
+        // Stripped links leave their text to be linked as usual
+        assert_eq!(
+            transform_with("[existing_links]\npolicy = \"strip\"\n"),
+            format!(
+                "{} GitHub\n",
+                make_osc8_link(&make_link_url(&main_rs, 3), "src/main.rs:3")
+            )
+        );
+        assert_eq!(
+            transform_with(
+                "[existing_links]\npolicy = \"allowlist\"\nallowed_schemes = [\"https\"]\nallowed_hosts = [\"github.com\"]\n"
+            ),
+            format!(
+                "{} {}\n",
+                make_osc8_link(&make_link_url(&main_rs, 3), "src/main.rs:3"),
+                make_osc8_link("https://github.com/", "GitHub")
+            )
+        );
+        assert_eq!(
+            transform_with("[existing_links]\npolicy = \"annotate\"\n"),
+            format!(
+                "{} <https://evil.example/> {} <https://github.com/>\n",
+                make_osc8_link("https://evil.example/", "src/main.rs:3"),
+                make_osc8_link("https://github.com/", "GitHub")
+            )
+        );
+
+        // Across chunks, and with a link replaced by another without being closed
+        let config: Config = toml::from_str("[existing_links]\npolicy = \"annotate\"\n").unwrap();
+        let mut transformer = Transformer::with_config(cwd.clone(), config);
+        assert_eq!(
+            transformer.transform("\x1b]8;;https://a.example/\x07one "),
+            "\x1b]8;;https://a.example/\x07one "
+        );
+        assert_eq!(
+            transformer.transform("\x1b]8;;https://b.example/\x07two\x1b]8;;\x07\n"),
+            "\x1b]8;;\x1b\\ <https://a.example/>\x1b]8;;https://b.example/\x07two\x1b]8;;\x07 <https://b.example/>\n"
+        );
+
+        let config: Config = toml::from_str("[existing_links]\npolicy = \"strip\"\n").unwrap();
+        let mut transformer = Transformer::with_config(cwd.clone(), config);
+        assert_eq!(
+            transformer.transform("\x1b]8;;https://a.example/\x07one "),
+            "one "
+        );
+        assert_eq!(transformer.transform("two\x1b]8;;\x07\n"), "two\n");
+    }
+
+    #[test]
+    fn test_escape_filter() {
+        let cwd = env::current_dir().unwrap();
+        let main_rs = get_crate_abs_path("src/main.rs");
+        let input =
+            "\x1b]52;c;cm0gLXJmIH4=\x07src/\x1b[21tmain.rs:4 \x1b]4;1;rgb:ff/00/00\x1b\\red\n";
+        assert_eq!(
+            transform(input, &cwd),
+            format!(
+                "\x1b]52;c;cm0gLXJmIH4=\x07{} \x1b]4;1;rgb:ff/00/00\x1b\\red\n",
+                make_osc8_link(&make_link_url(&main_rs, 4), "src/\x1b[21tmain.rs:4")
+            )
+        );
+
+        let config: Config = toml::from_str(
+            "[escape_filter]\nclipboard = \"drop\"\ntitle_report = \"drop\"\npalette = \"show\"\n",
+        )
+        .unwrap();
+        let mut transformer = Transformer::with_config(cwd.clone(), config);
+        assert_eq!(
+            transformer.transform(input),
+            format!(
+                "{} ^[]4;1;rgb:ff/00/00^[\\red\n",
+                make_osc8_link(&make_link_url(&main_rs, 4), "src/main.rs:4")
+            )
+        );
+
+        let config: Config = toml::from_str("[escape_filter]\ndecrqss = \"drop\"\n").unwrap();
+        let mut transformer = Transformer::with_config(cwd.clone(), config);
+        assert_eq!(transformer.transform("a\x1bP$qm\x1b\\b\n"), "ab\n");
+    }
+
+    #[test]
     fn test_python_traceback_with_internal_ansi() {
-        // Test case demonstrating the limitation with ANSI codes inside a potential match.
+        // Python 3.13 colours the file name and line number of each frame separately
         let cwd = env::current_dir().unwrap();
         let abs_path = get_crate_abs_path("Cargo.toml"); // Use existing file
         let abs_path_str = abs_path.to_string_lossy();
         let line_num = 5; // Arbitrary line number
 
-        // 1. Define the exact text segment that *should* be hyperlinked (incl. internal ANSI)
+        // The one link wraps the whole segment, including its internal ANSI codes
         let text_to_be_hyperlinked = format!(
             "  File \x1b[35m\"{}\"\x1b[0m, line \x1b[35m{}\x1b[0m",
             abs_path_str, line_num
         );
 
-        // 2. Construct the full input string using the segment above
         let input = format!(
             "Traceback (most recent call last):\n{}, in \x1b[35m<module>\x1b[0m\n    raise ValueError\n \x1b[1;35mValueError\x1b[0m",
             text_to_be_hyperlinked
         );
 
-        // --- Actual ---
         let actual = transform(&input, &cwd);
-
-        // --- Assert ---
-        // This assertion should FAIL with the current transform logic because it cannot
-        // match the pattern across the internal ANSI codes.
-        // 3. Define the *expected* (linked) output (This is the target state)
         let link_url = make_link_url(&abs_path, line_num);
         let expected_linked_segment = make_osc8_link(&link_url, &text_to_be_hyperlinked);
         let expected = format!(
@@ -444,14 +1713,21 @@ mod tests {
             actual, expected,
             "Test expects hyperlink around 'File..., line...' segment, including internal ANSI"
         );
+    }
 
-        // --- To make this test PASS with CURRENT logic (demonstrating the bug): ---
-        // Comment out the assertion above and uncomment the one below.
-        // This asserts that the output is identical to the input (no link added).
-        // assert_eq!(
-        //     actual,
-        //     input, // Assert no change occurred
-        //     "Test currently expects NO link due to internal ANSI (modify test to see failure)"
-        // );
+    #[test]
+    fn test_file_path_with_internal_ansi() {
+        // grep --color and ripgrep colour the path, line and column differently
+        let cwd = env::current_dir().unwrap();
+        let abs_path = get_crate_abs_path("src/main.rs");
+        let located = "\x1b[35msrc/main.rs\x1b[0m:\x1b[32m12\x1b[0m:\x1b[32m4\x1b[0m";
+        let input = format!("{}:fn main() {{\x1b[K\n", located);
+        let url = format!("cursor://file/{}:12:4", abs_path.display());
+        // The leading SGR stays outside the link; the trailing reset, but not the erase, inside
+        let expected = format!(
+            "\x1b[35m{}:fn main() {{\x1b[K\n",
+            make_osc8_link(&url, &located["\x1b[35m".len()..])
+        );
+        assert_eq!(transform(&input, &cwd), expected);
     }
 }
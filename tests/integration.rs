@@ -510,7 +510,7 @@ fn test_terminal_resize_pane() {
     let final_cols: u32 = content2
         .lines()
         .filter_map(|l| l.trim().parse().ok())
-        .last()
+        .next_back()
         .unwrap_or(0);
 
     assert!(
@@ -522,3 +522,100 @@ fn test_terminal_resize_pane() {
         content2
     );
 }
+
+// A 1x1 red PNG, base64-encoded for the kitty graphics protocol
+const RED_PIXEL_PNG: &str =
+    "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAIAAACQd1PeAAAADElEQVR4nGP4z8AAAAMBAQDJ/pLvAAAAAElFTkSuQmCC";
+
+/// Run xolmis on a PTY with a "shell" that only prints `payload` followed by a plain path, and
+/// return the bytes xolmis writes. Checking the bytes, rather than what a terminal makes of them,
+/// shows whether links were inserted inside the payload; the plain path after it shows that
+/// links are inserted at all.
+fn xolmis_output_for(payload: &str) -> String {
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+
+    ensure_built();
+    let id = SESSION_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let dir = std::env::temp_dir();
+    let payload_path = dir.join(format!("xolmis_payload_{}_{}", std::process::id(), id));
+    let shell_path = dir.join(format!("xolmis_shell_{}_{}.sh", std::process::id(), id));
+    std::fs::write(&payload_path, format!("{}\nsrc/main.rs:10\n", payload)).unwrap();
+    std::fs::write(
+        &shell_path,
+        format!("#!/bin/sh\nexec cat {}\n", payload_path.display()),
+    )
+    .unwrap();
+    std::fs::set_permissions(&shell_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let (mut pty, pts) = pty_process::blocking::open().unwrap();
+    let mut child = pty_process::blocking::Command::new("./target/debug/xolmis")
+        .env("SHELL", &shell_path)
+        .spawn(pts)
+        .unwrap();
+
+    // Reading the PTY fails once xolmis has exited and closed it
+    let (sender, receiver) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let mut buffer = [0; 4096];
+        while let Ok(n) = pty.read(&mut buffer) {
+            if n == 0 {
+                break;
+            }
+            output.extend_from_slice(&buffer[..n]);
+        }
+        let _ = sender.send(output);
+    });
+    let output = receiver.recv_timeout(Duration::from_secs(10));
+    let _ = child.kill();
+    let _ = child.wait();
+    std::fs::remove_file(&payload_path).unwrap();
+    std::fs::remove_file(&shell_path).unwrap();
+    String::from_utf8_lossy(&output.expect("xolmis did not exit")).into_owned()
+}
+
+/// Assert that `payload` came through unchanged, and that the path printed after it was linked.
+fn assert_passed_through(payload: &str) {
+    let output = xolmis_output_for(payload);
+    assert!(
+        output.contains(payload),
+        "payload was changed:\n{:?}",
+        output
+    );
+    assert!(
+        output.contains("\x1b]8;"),
+        "the path after the payload was not linked:\n{:?}",
+        output
+    );
+}
+
+// Unlike the tmux passthrough below, kitty and sixel payloads came through unchanged before they
+// were made opaque elements too; these tests keep it that way, with payloads long enough to span
+// several reads of the PTY.
+#[test]
+fn test_kitty_image_passthrough() {
+    // A real image, then an APC string whose payload looks like file paths
+    assert_passed_through(&format!(
+        "\x1b_Gf=100,a=T,t=d;{}\x1b\\\x1b_Gf=100,a=T;{}src/main.rs:10 Cargo.toml:1\x1b\\",
+        RED_PIXEL_PNG,
+        "A".repeat(5000)
+    ));
+}
+
+#[test]
+fn test_sixel_image_passthrough() {
+    assert_passed_through(&format!(
+        "\x1bPq\"1;1;6;6#0;2;100;0;0{}-src/main.rs:10\x1b\\",
+        "#0~~~~~~".repeat(1000)
+    ));
+}
+
+#[test]
+fn test_tmux_passthrough() {
+    // ESC is doubled inside a tmux passthrough string
+    assert_passed_through(&format!(
+        "\x1bPtmux;\x1b\x1b_Gf=100,a=T;{}\x1b\x1b\\\x1b\\\x1bPtmux;\x1b\x1b[31msrc/main.rs:11\x1b\\",
+        RED_PIXEL_PNG
+    ));
+}